@@ -13,11 +13,17 @@ CREATE TABLE IF NOT EXISTS events (
   title TEXT NOT NULL,
   description TEXT,
   location TEXT,
+  attendees TEXT,                      -- JSON array of attendee names
   start_time INTEGER NOT NULL,         -- Unix timestamp
   end_time INTEGER NOT NULL,           -- Unix timestamp
+  all_day BOOLEAN DEFAULT FALSE,       -- Date-only event, start/end are UTC midnights, end exclusive
+  recurrence TEXT,                     -- RRULE/EXDATE/RDATE lines of a series master, newline separated
+  recurring_event_id TEXT,             -- Series master of a modified or cancelled occurrence
+  original_start_time INTEGER,         -- Unix timestamp the occurrence was scheduled at in its series
+  time_zone TEXT,                      -- IANA time zone the series recurs in
   created_at INTEGER DEFAULT (unixepoch()),
   modified_at INTEGER DEFAULT (unixepoch()),
-  deleted BOOLEAN DEFAULT FALSE        -- Soft delete for sync safety
+  deleted BOOLEAN DEFAULT FALSE,       -- Soft delete for sync safety
 
   -- Unique constraint includes calendar_id since event_id is only unique within calendar
   UNIQUE(event_id, calendar_id)
@@ -31,7 +37,8 @@ CREATE TABLE IF NOT EXISTS calendars (
     access_role TEXT,                -- owner/writer/reader
     sync_enabled BOOLEAN DEFAULT TRUE,
     last_sync_time INTEGER DEFAULT (unixepoch()),
-    etag TEXT                        -- For incremental sync
+    etag TEXT,                       -- For incremental sync
+    sync_token TEXT                  -- nextSyncToken from the last events.list of this calendar
 );
 
 -- Sync metadata table to track sync state per source
@@ -51,16 +58,27 @@ CREATE TABLE IF NOT EXISTS sync_metadata (
 
   -- For org-mode events: which calendar should they sync to?
   target_calendar_id TEXT,         -- Where to create this event in Google Calendar
+  gcal_event_id TEXT,              -- ID of the event mirroring it in target_calendar_id
 
   -- General sync tracking
   last_sync_time INTEGER DEFAULT (unixepoch()),
   needs_upload BOOLEAN DEFAULT FALSE,   -- Changed locally, needs sync to external
   sync_conflict BOOLEAN DEFAULT FALSE,  -- Conflict detected during sync
+  remote_version TEXT,                  -- JSON of the conflicting remote event while sync_conflict is set
+  upload_attempts INTEGER DEFAULT 0,    -- Failed uploads since the last local change
+  upload_error TEXT,                    -- Error from the last failed upload
+  upload_retry_at INTEGER,              -- Unix timestamp before which the upload isn't retried
 
-  FOREIGN KEY (local_id) REFERENCES events(local_id) ON DELETE CASCADE
+  FOREIGN KEY (local_id) REFERENCES events(local_id) ON DELETE CASCADE,
   FOREIGN KEY (target_calendar_id) REFERENCES calendars(calendar_id)
 );
 
+-- Org files as of their last import, including files without any events
+CREATE TABLE IF NOT EXISTS org_files (
+  path TEXT PRIMARY KEY,                -- Canonical path of the .org file
+  content_hash TEXT NOT NULL            -- Hash of its content when it was last imported
+);
+
 -- Indexes
 CREATE INDEX IF NOT EXISTS idx_events_gcal_calendar 
     ON events(event_id, calendar_id);
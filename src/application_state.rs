@@ -1,28 +1,335 @@
-use crate::{database::Database, google_calendar_api::GoogleCalendarAPI};
+use std::{path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+
+use tokio::{sync::{watch, MappedMutexGuard, MutexGuard, Notify}, task::JoinHandle};
+
+use crate::{
+    auth::AuthFlow,
+    database::{Database, PendingUpload},
+    error::{log, UltimaError},
+    google_calendar_api::{is_sync_token_expired, EventListOptions, GoogleCalendarAPI},
+    event::SourceType,
+    org::{import_org_file, mirror_event, OrgMirrors},
+};
+
+/// How often the upload worker checks for retries that became due, when no local change wakes it
+const UPLOAD_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Delay before retrying a failed sync, doubled after every further failure
+const SYNC_BASE_BACKOFF: Duration = Duration::from_secs(15);
+const SYNC_MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// What the sync worker is up to, shown in the TUI's title bar
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncStatus {
+    Idle,
+    Syncing,
+    Error(String), // The last sync failed, it is retried with backoff
+    Offline, // Google couldn't be reached, the cached data is shown
+}
+
+/// Google Calendar connection, None while signed out or offline
+type Connection = tokio::sync::Mutex<Option<GoogleCalendarAPI>>;
 
 pub struct ApplicationState {
-    gcal_api: GoogleCalendarAPI,
-    db: Database,
+    gcal_api: Arc<Connection>,
+    credentials: Arc<(PathBuf, PathBuf)>, // Client secret and cached token, to connect in the background
+    db: Arc<Mutex<Database>>,
+    upload_trigger: Arc<Notify>,
+    upload_worker: Option<JoinHandle<()>>,
+    sync_trigger: Arc<Notify>,
+    sync_worker: Option<JoinHandle<()>>,
+    sync_status: watch::Sender<SyncStatus>,
+    changes: watch::Sender<()>,
+}
+
+/// The part of the application state the TUI works with while sync runs in the background
+#[derive(Clone)]
+pub struct StateHandle {
+    pub db: Arc<Mutex<Database>>,
+    pub upload_trigger: Arc<Notify>, // Wake the upload worker after a local change
+    pub sync_trigger: Arc<Notify>, // Fetch remote changes now
+    pub sync_status: watch::Receiver<SyncStatus>,
+    pub changes: watch::Receiver<()>, // Marked as changed whenever a background task wrote to the database
 }
 
 impl ApplicationState {
-    pub fn new(gcal_api: GoogleCalendarAPI, db: Database) -> Self {
+    /// State working from the database alone. The background tasks connect to Google Calendar
+    /// with the token cached at `token_path` once they can, see `start_sync_worker`.
+    pub fn new(db: Arc<Mutex<Database>>, secret_path: PathBuf, token_path: PathBuf) -> Self {
         Self {
-            gcal_api,
+            gcal_api: Arc::new(tokio::sync::Mutex::new(None)),
+            credentials: Arc::new((secret_path, token_path)),
             db,
+            upload_trigger: Arc::new(Notify::new()),
+            upload_worker: None,
+            sync_trigger: Arc::new(Notify::new()),
+            sync_worker: None,
+            sync_status: watch::Sender::new(SyncStatus::Idle),
+            changes: watch::Sender::new(()),
         }
     }
 
-    /// Import all Google Calendar events into the database
-    // TODO, make it possible to select which calendars to add before adding
-    pub async fn init_gcal(&mut self) -> Result<(), ()> {
-        let mut calendars = self.gcal_api.get_calendars().await;
-        for mut calendar in calendars.expect("Unable to retrieve calendars") {
-            self.db.sync_calendar(&mut calendar);
-            for mut event in calendar.events {
-                self.db.sync_event(&mut event);
-            }
+    pub fn handle(&self) -> StateHandle {
+        StateHandle {
+            db: self.db.clone(),
+            upload_trigger: self.upload_trigger.clone(),
+            sync_trigger: self.sync_trigger.clone(),
+            sync_status: self.sync_status.subscribe(),
+            changes: self.changes.subscribe(),
+        }
+    }
+
+    /// Connect to Google Calendar, running `flow` if there is no valid cached token. This asks
+    /// the user to sign in, so it has to run before the TUI takes over the terminal.
+    pub async fn sign_in(&self, flow: AuthFlow) -> Result<(), UltimaError> {
+        let (secret_path, token_path) = self.credentials.as_ref();
+        let gcal_api = GoogleCalendarAPI::new(secret_path, token_path, Some(flow)).await?;
+        *self.gcal_api.lock().await = Some(gcal_api);
+        Ok(())
+    }
+
+    /// Store the calendars of the account, without their events, so that the user can pick which
+    /// ones to sync before anything is imported. Only the calendars listed in `enabled` are kept,
+    /// stored calendars missing from it are disabled and lose their cached events.
+    pub async fn refresh_calendars(&self, enabled: Option<&[String]>) -> Result<(), UltimaError> {
+        let gcal_api = connect(&self.gcal_api, &self.credentials).await?;
+        refresh_calendars(&gcal_api, &self.db, enabled).await
+    }
+
+    /// Import the events of the given .org files. Files whose content hasn't changed since the
+    /// last import are skipped. Events with a mirror calendar in `mirrors` are queued for upload
+    /// to it, see `start_upload_worker`.
+    pub fn import_org_files<P: AsRef<Path>>(&self, paths: &[P], mirrors: &OrgMirrors) -> Result<(), UltimaError> {
+        for path in paths {
+            let path = path.as_ref();
+            import_org_file(&self.db, path, mirrors)
+                .map_err(|error| UltimaError::Org(format!("Unable to import {}: {error}", path.display())))?;
         }
+        self.upload_trigger.notify_one();
         Ok(())
     }
+
+    /// Spawn the background task that pushes local changes to Google Calendar. Failed uploads
+    /// stay queued with their error and are retried with exponential backoff.
+    pub fn start_upload_worker(&mut self) {
+        if self.upload_worker.is_some() {
+            return;
+        }
+        let gcal_api = self.gcal_api.clone();
+        let db = self.db.clone();
+        let trigger = self.upload_trigger.clone();
+        let changes = self.changes.clone();
+        self.upload_worker = Some(tokio::spawn(async move {
+            loop {
+                if push_local_changes(&gcal_api, &db).await {
+                    changes.send_replace(());
+                }
+                tokio::select! {
+                    _ = trigger.notified() => {}
+                    _ = tokio::time::sleep(UPLOAD_POLL_INTERVAL) => {}
+                }
+            }
+        }));
+    }
+
+    /// Spawn the background task that refreshes the calendar list and fetches remote changes of
+    /// the enabled calendars every `interval` and whenever the sync trigger is notified,
+    /// connecting first if needed. Calendars not listed in `enabled` are left out entirely.
+    /// Errors, such as being offline, are logged and retried with exponential backoff.
+    pub fn start_sync_worker(&mut self, enabled: Option<Vec<String>>, interval: Duration) {
+        if self.sync_worker.is_some() {
+            return;
+        }
+        let gcal_api = self.gcal_api.clone();
+        let credentials = self.credentials.clone();
+        let db = self.db.clone();
+        let trigger = self.sync_trigger.clone();
+        let status = self.sync_status.clone();
+        let changes = self.changes.clone();
+        self.sync_worker = Some(tokio::spawn(async move {
+            let mut failures = 0;
+            loop {
+                tokio::select! {
+                    _ = trigger.notified() => {}
+                    _ = tokio::time::sleep(sync_delay(interval, failures)) => {}
+                }
+                status.send_replace(SyncStatus::Syncing);
+                match sync(&gcal_api, &credentials, &db, enabled.as_deref()).await {
+                    Ok(()) => {
+                        failures = 0;
+                        status.send_replace(SyncStatus::Idle);
+                        changes.send_replace(());
+                    }
+                    Err(error) => {
+                        failures += 1;
+                        log(&error);
+                        status.send_replace(if error.is_offline() { SyncStatus::Offline } else { SyncStatus::Error(error.to_string()) });
+                    }
+                }
+            }
+        }));
+    }
+
+    /// Sync in the background as soon as possible, see `start_sync_worker`
+    pub fn request_sync(&self) {
+        self.sync_trigger.notify_one();
+    }
+}
+
+impl Drop for ApplicationState {
+    fn drop(&mut self) {
+        for worker in [self.upload_worker.take(), self.sync_worker.take()].into_iter().flatten() {
+            worker.abort();
+        }
+    }
+}
+
+/// Time until the next scheduled sync after `failures` failed ones in a row
+fn sync_delay(interval: Duration, failures: u32) -> Duration {
+    match failures {
+        0 => interval,
+        failures => SYNC_BASE_BACKOFF.saturating_mul(1 << (failures - 1).min(20)).min(SYNC_MAX_BACKOFF),
+    }
+}
+
+/// The Google Calendar connection, made with the cached token if there is none yet. It stays
+/// locked while the guard lives, so that syncs and uploads take turns.
+async fn connect<'a>(gcal_api: &'a Connection, credentials: &(PathBuf, PathBuf)) -> Result<MappedMutexGuard<'a, GoogleCalendarAPI>, UltimaError> {
+    let mut guard = gcal_api.lock().await;
+    if guard.is_none() {
+        let (secret_path, token_path) = credentials;
+        *guard = Some(GoogleCalendarAPI::new(secret_path, token_path, None).await?);
+    }
+    Ok(MutexGuard::map(guard, |gcal_api| gcal_api.as_mut().unwrap()))
+}
+
+async fn sync(gcal_api: &Connection, credentials: &(PathBuf, PathBuf), db: &Mutex<Database>, enabled: Option<&[String]>) -> Result<(), UltimaError> {
+    let gcal_api = connect(gcal_api, credentials).await?;
+    refresh_calendars(&gcal_api, db, enabled).await?;
+    sync_enabled_calendars(&gcal_api, db).await
+}
+
+async fn refresh_calendars(gcal_api: &GoogleCalendarAPI, db: &Mutex<Database>, enabled: Option<&[String]>) -> Result<(), UltimaError> {
+    let calendars = gcal_api.get_calendars().await?;
+    let calendars = calendars.into_iter()
+        .filter(|calendar| enabled.is_none_or(|enabled| enabled.contains(&calendar.id)));
+    let mut db = db.lock().unwrap();
+    for calendar in calendars {
+        db.sync_calendar(&calendar)?;
+    }
+    if let Some(enabled) = enabled {
+        db.disable_calendars_except(enabled)?;
+    }
+    Ok(())
+}
+
+/// Fetch the events of the stored calendars with syncing enabled. Calendars synced before only
+/// fetch the changes since their stored sync token.
+async fn sync_enabled_calendars(gcal_api: &GoogleCalendarAPI, db: &Mutex<Database>) -> Result<(), UltimaError> {
+    let calendars = db.lock().unwrap().calendars()?;
+    for calendar in calendars.iter().filter(|calendar| calendar.sync_enabled) {
+        sync_calendar_events(gcal_api, db, &calendar.id).await?;
+    }
+    Ok(())
+}
+
+/// Fetch a calendar's events incrementally using its own sync token, falling back to a full
+/// resync when Google reports the token as expired
+async fn sync_calendar_events(gcal_api: &GoogleCalendarAPI, db: &Mutex<Database>, calendar_id: &str) -> Result<(), UltimaError> {
+    let sync_token = db.lock().unwrap().calendar_sync_token(calendar_id)?;
+    let options = EventListOptions::default();
+    let sync = match gcal_api.get_events(calendar_id, sync_token.as_deref(), &options).await {
+        Err(error) if is_sync_token_expired(&error) => {
+            db.lock().unwrap().reset_calendar_sync(calendar_id)?;
+            gcal_api.get_events(calendar_id, None, &options).await?
+        }
+        result => result?,
+    };
+
+    let mut db = db.lock().unwrap();
+    for event in &sync.events {
+        db.sync_event(event)?;
+    }
+    for cancelled in &sync.cancelled {
+        match (&cancelled.recurring_event_id, cancelled.original_start_time) {
+            (Some(recurring_event_id), Some(original_start_time)) => {
+                db.cancel_occurrence(&cancelled.event_id, calendar_id, recurring_event_id, original_start_time)?;
+            }
+            _ => db.remove_cancelled_event(&cancelled.event_id, calendar_id)?,
+        }
+    }
+    db.set_calendar_sync_token(calendar_id, sync.next_sync_token.as_deref())?;
+    Ok(())
+}
+
+/// Push every due upload, recording failures in the database so they show up in the TUI. Uploads
+/// stay queued until the sync worker connected. Returns whether any row was uploaded, purged or
+/// got a failure recorded.
+async fn push_local_changes(gcal_api: &Connection, db: &Mutex<Database>) -> bool {
+    let gcal_api = gcal_api.lock().await;
+    let Some(gcal_api) = gcal_api.as_ref() else {
+        return false;
+    };
+    let Ok(pending) = db.lock().unwrap().pending_uploads() else {
+        return false;
+    };
+    let mut changed = false;
+    for upload in pending {
+        changed |= match push_upload(gcal_api, db, &upload).await {
+            Ok(()) => true,
+            Err(error) => db.lock().unwrap().record_upload_failure(upload.local_id, &error.to_string()).is_ok(),
+        };
+    }
+    changed
+}
+
+async fn push_upload(gcal_api: &GoogleCalendarAPI, db: &Mutex<Database>, upload: &PendingUpload) -> Result<(), UltimaError> {
+    let event = &upload.event;
+    if upload.deleted {
+        if upload.gcal_synced {
+            gcal_api.delete_event(&event.calendar_id, &event.event_id).await?;
+        }
+        // A deleted occurrence keeps its row so that it stays hidden from the series
+        if event.recurring_event_id.is_some() {
+            db.lock().unwrap().mark_uploaded(upload.local_id, "")?;
+        } else {
+            db.lock().unwrap().purge_event(upload.local_id)?;
+        }
+        return Ok(());
+    }
+
+    // Org events are pushed as a mirror event in their target calendar
+    let mirror;
+    let event = match (&event.source_type, &upload.target_calendar_id) {
+        (SourceType::OrgMode, Some(target_calendar_id)) => {
+            mirror = mirror_event(event, target_calendar_id);
+            &mirror
+        }
+        (SourceType::OrgMode, None) => {
+            db.lock().unwrap().mark_uploaded(upload.local_id, "")?;
+            return Ok(());
+        }
+        (SourceType::GoogleCalendar, _) => event,
+    };
+    let etag = if upload.gcal_synced {
+        gcal_api.patch_event(event).await?
+    } else {
+        gcal_api.insert_event(event).await?
+    };
+    db.lock().unwrap().mark_uploaded(upload.local_id, &etag)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_syncs_back_off_up_to_a_limit() {
+        let interval = Duration::from_secs(300);
+        assert_eq!(sync_delay(interval, 0), interval);
+        assert_eq!(sync_delay(interval, 1), SYNC_BASE_BACKOFF);
+        assert_eq!(sync_delay(interval, 3), SYNC_BASE_BACKOFF * 4);
+        assert_eq!(sync_delay(interval, 40), SYNC_MAX_BACKOFF);
+    }
 }
@@ -0,0 +1,255 @@
+use std::{fs, future::Future, io, path::{Path, PathBuf}, pin::Pin, str::FromStr};
+
+use chrono::{DateTime, Local};
+use google_calendar3::{
+    hyper_rustls::HttpsConnector,
+    hyper_util::client::legacy::connect::HttpConnector,
+    yup_oauth2::{
+        self, authenticator::Authenticator, authenticator_delegate::InstalledFlowDelegate, storage::TokenInfo,
+    },
+};
+use serde::Deserialize;
+use tokio::io::AsyncBufReadExt;
+
+use crate::{error::UltimaError, google_calendar_api::GoogleCalendarAPI};
+
+pub const SCOPES: [&str; 4] = [
+    "https://www.googleapis.com/auth/calendar",
+    "https://www.googleapis.com/auth/calendar.events",
+    "https://www.googleapis.com/auth/calendar.readonly",
+    "https://www.googleapis.com/auth/calendar.events.readonly",
+];
+
+const REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+
+/// How the user grants ultima access to their calendars the first time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthFlow {
+    #[default]
+    Browser, // Open a browser and wait for Google to redirect it to a local server
+    Device, // Enter a code at google.com/device from any other device. Needs a "TVs and Limited Input devices" OAuth client.
+    Manual, // Open the link anywhere and paste back the address the browser was redirected to
+}
+
+impl FromStr for AuthFlow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "browser" => Ok(AuthFlow::Browser),
+            "device" => Ok(AuthFlow::Device),
+            "manual" => Ok(AuthFlow::Manual),
+            _ => Err(format!("invalid auth flow \"{s}\", expected browser, device or manual")),
+        }
+    }
+}
+
+/// Authenticator for the client secret at `secret_path`, caching the token at `token_path`. A valid
+/// cached token is used as is, otherwise `flow` is run to get a new one. Without a flow the cached
+/// token is only refreshed, nothing is asked of the user, which is what happens while the TUI
+/// owns the terminal.
+pub async fn authenticate(secret_path: &Path, token_path: &Path, flow: Option<AuthFlow>) -> Result<Authenticator<HttpsConnector<HttpConnector>>, UltimaError> {
+    let secret = yup_oauth2::read_application_secret(secret_path)
+        .await
+        .map_err(|error| UltimaError::Auth(format!("Unable to read client secret {}: {error}", secret_path.display())))?;
+    let auth = match flow {
+        None => yup_oauth2::InstalledFlowAuthenticator::builder(secret, yup_oauth2::InstalledFlowReturnMethod::Interactive)
+            .flow_delegate(Box::new(NoFlowDelegate))
+            .persist_tokens_to_disk(token_path)
+            .build()
+            .await,
+        Some(AuthFlow::Browser) => yup_oauth2::InstalledFlowAuthenticator::builder(secret, yup_oauth2::InstalledFlowReturnMethod::HTTPRedirect)
+            .persist_tokens_to_disk(token_path)
+            .build()
+            .await,
+        Some(AuthFlow::Device) => yup_oauth2::DeviceFlowAuthenticator::builder(secret)
+            .persist_tokens_to_disk(token_path)
+            .build()
+            .await,
+        Some(AuthFlow::Manual) => yup_oauth2::InstalledFlowAuthenticator::builder(secret, yup_oauth2::InstalledFlowReturnMethod::Interactive)
+            .flow_delegate(Box::new(ManualFlowDelegate))
+            .persist_tokens_to_disk(token_path)
+            .build()
+            .await,
+    }
+    .map_err(|error| UltimaError::Auth(error.to_string()))?;
+
+    auth.token(&SCOPES).await.map_err(|error| match error {
+        yup_oauth2::Error::HttpError(error) => UltimaError::Offline(error.to_string()),
+        error => UltimaError::Auth(error.to_string()),
+    })?;
+    Ok(auth)
+}
+
+/// Sign in again with `flow`, replacing the cached token only once the new one was granted, so
+/// that a failed or cancelled sign-in leaves the old token working
+pub async fn login(secret_path: &Path, token_path: &Path, flow: AuthFlow) -> Result<(), UltimaError> {
+    let mut new_path = token_path.as_os_str().to_owned();
+    new_path.push(".new");
+    let new_path = PathBuf::from(new_path);
+    forget(&new_path)?;
+    if let Err(error) = authenticate(secret_path, &new_path, Some(flow)).await {
+        let _ = forget(&new_path);
+        return Err(error);
+    }
+    fs::rename(&new_path, token_path)
+        .map_err(|error| UltimaError::Auth(format!("Unable to replace {}: {error}", token_path.display())))
+}
+
+/// Asks for the address the browser ended up at after granting access. Nothing listens on
+/// localhost, so the page fails to load, but its address holds the authorization code.
+struct ManualFlowDelegate;
+
+impl InstalledFlowDelegate for ManualFlowDelegate {
+    fn redirect_uri(&self) -> Option<&str> {
+        Some("http://localhost")
+    }
+
+    fn present_user_url<'a>(&'a self, url: &'a str, _need_code: bool) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>> {
+        Box::pin(async move {
+            println!("Open this link in a browser on any device and grant access:\n\n{url}\n");
+            println!("The browser then fails to load a page on localhost. Paste the address it shows here:");
+            let mut input = String::new();
+            tokio::io::BufReader::new(tokio::io::stdin())
+                .read_line(&mut input)
+                .await
+                .map_err(|error| format!("Unable to read the address: {error}"))?;
+            auth_code(&input).ok_or_else(|| "No authorization code in the pasted address".to_string())
+        })
+    }
+}
+
+/// Refuses to sign in, used when only a cached token may be used
+struct NoFlowDelegate;
+
+impl InstalledFlowDelegate for NoFlowDelegate {
+    fn present_user_url<'a>(&'a self, _url: &'a str, _need_code: bool) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>> {
+        Box::pin(async { Err("Not signed in or the token was revoked, run `ultima auth login`".to_string()) })
+    }
+}
+
+/// Authorization code in a redirect address such as http://localhost/?code=4%2F0Ab&scope=...
+/// The bare code is accepted too.
+fn auth_code(input: &str) -> Option<String> {
+    let input = input.trim();
+    let code = match input.split_once('?') {
+        Some((_, query)) => query.split('&').find_map(|pair| pair.strip_prefix("code="))?,
+        None if input.contains("://") => return None,
+        None => input,
+    };
+    let code = percent_decode(code)?;
+    (!code.is_empty()).then_some(code)
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// An entry of the token file written by `persist_tokens_to_disk`
+#[derive(Deserialize)]
+struct StoredToken {
+    scopes: Vec<String>,
+    token: TokenInfo,
+}
+
+fn read_tokens(token_path: &Path) -> Result<Option<Vec<StoredToken>>, UltimaError> {
+    let text = match fs::read_to_string(token_path) {
+        Ok(text) => text,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(UltimaError::Auth(format!("Unable to read {}: {error}", token_path.display()))),
+    };
+    serde_json::from_str(&text)
+        .map(Some)
+        .map_err(|error| UltimaError::Auth(format!("Invalid token file {}: {error}", token_path.display())))
+}
+
+/// Lines describing the cached token, printed by `ultima auth status`
+pub fn status(token_path: &Path) -> Result<Vec<String>, UltimaError> {
+    let Some(tokens) = read_tokens(token_path)? else {
+        return Ok(vec![format!("Not signed in, no token at {}", token_path.display())]);
+    };
+    let mut lines = vec![format!("Token: {}", token_path.display())];
+    for StoredToken { scopes, token } in tokens {
+        let expiry = token.expires_at
+            .and_then(|expires_at| DateTime::from_timestamp(expires_at.unix_timestamp(), 0))
+            .map(|expires_at| expires_at.with_timezone(&Local));
+        lines.push(format!("Scopes: {}", scopes.join(" ")));
+        lines.push(match expiry {
+            Some(expiry) if expiry > Local::now() => format!("Access token valid until {}", expiry.format("%Y-%m-%d %H:%M")),
+            Some(expiry) => format!("Access token expired at {}", expiry.format("%Y-%m-%d %H:%M")),
+            None => "Access token has no expiry".to_string(),
+        });
+        lines.push(if token.refresh_token.is_some() {
+            "Refresh token present, the access token is renewed automatically".to_string()
+        } else {
+            "No refresh token, run `ultima auth login` once the access token expires".to_string()
+        });
+    }
+    Ok(lines)
+}
+
+/// Drop the cached token so that the next `authenticate` runs the flow again
+pub fn forget(token_path: &Path) -> Result<(), UltimaError> {
+    match fs::remove_file(token_path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound =>
+            Err(UltimaError::Auth(format!("Unable to remove {}: {error}", token_path.display()))),
+        _ => Ok(()),
+    }
+}
+
+/// Revoke the cached token with Google and delete it. Returns false if there was none.
+pub async fn revoke(token_path: &Path) -> Result<bool, UltimaError> {
+    let Some(tokens) = read_tokens(token_path)? else {
+        return Ok(false);
+    };
+
+    let client = GoogleCalendarAPI::http_client()?;
+    // Revoking the refresh token revokes its access tokens as well
+    for token in tokens.iter().filter_map(|stored| stored.token.refresh_token.as_ref().or(stored.token.access_token.as_ref())) {
+        let request = hyper::Request::post(REVOKE_URL)
+            .header(hyper::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(google_calendar3::common::to_body(Some(format!("token={token}"))))
+            .map_err(|error| UltimaError::Auth(error.to_string()))?;
+        let response = client.request(request).await
+            .map_err(|error| UltimaError::from(google_calendar3::Error::HttpError(error)))?;
+        // 400 means the token was revoked or expired already
+        let status = response.status();
+        if !status.is_success() && status != hyper::StatusCode::BAD_REQUEST {
+            return Err(UltimaError::Auth(format!("Google refused to revoke the token: {status}")));
+        }
+    }
+
+    forget(token_path)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_code_from_a_pasted_address() {
+        assert_eq!(auth_code("http://localhost/?code=4%2F0AbCd-e_f&scope=https%3A%2F%2Fwww.googleapis.com\n").as_deref(), Some("4/0AbCd-e_f"));
+        assert_eq!(auth_code("http://localhost/?state=x&code=abc").as_deref(), Some("abc"));
+        assert_eq!(auth_code("  4/0AbCd  ").as_deref(), Some("4/0AbCd"));
+        assert_eq!(auth_code("http://localhost/?error=access_denied"), None);
+        assert_eq!(auth_code("http://localhost/"), None);
+        assert_eq!(auth_code("http://localhost/?code=%2"), None);
+        assert_eq!(auth_code(""), None);
+        assert_eq!("device".parse(), Ok(AuthFlow::Device));
+        assert!("oob".parse::<AuthFlow>().is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Color, Stylize},
+    symbols::border,
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, StatefulWidget, Widget},
+};
+
+use crate::{event::GcalCalendar, tui::parse_hex_color};
+
+/// What the TUI should do after the calendar list handled a key press
+pub enum CalendarListAction {
+    None,
+    Close,
+    Toggle { calendar_id: String, enabled: bool },
+}
+
+/// Popup checklist of the account's calendars, picking which ones are synced
+pub struct CalendarList {
+    calendars: Vec<GcalCalendar>,
+    state: ListState,
+}
+
+impl CalendarList {
+    pub fn new(calendars: Vec<GcalCalendar>) -> Self {
+        let state = ListState::default().with_selected(if calendars.is_empty() { None } else { Some(0) });
+        Self { calendars, state }
+    }
+
+    pub fn handle_key_event(&mut self, key_event: KeyEvent) -> CalendarListAction {
+        match key_event.code {
+            KeyCode::Esc | KeyCode::Enter | KeyCode::Char('q') => return CalendarListAction::Close,
+            KeyCode::Down | KeyCode::Char('j') => self.state.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.state.select_previous(),
+            KeyCode::Char(' ') => {
+                if let Some(calendar) = self.state.selected().and_then(|i| self.calendars.get_mut(i)) {
+                    calendar.sync_enabled = !calendar.sync_enabled;
+                    return CalendarListAction::Toggle { calendar_id: calendar.id.clone(), enabled: calendar.sync_enabled };
+                }
+            }
+            _ => {}
+        }
+        CalendarListAction::None
+    }
+
+    /// Undo a toggle that couldn't be saved
+    pub fn set_enabled(&mut self, calendar_id: &str, enabled: bool) {
+        if let Some(calendar) = self.calendars.iter_mut().find(|calendar| calendar.id == calendar_id) {
+            calendar.sync_enabled = enabled;
+        }
+    }
+}
+
+impl Widget for &mut CalendarList {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let instructions = Line::from(vec![
+            " Toggle ".into(),
+            "<Space>".blue().bold(),
+            " Done ".into(),
+            "<Enter>".blue().bold(),
+            " ".into(),
+        ]);
+        let block = Block::bordered()
+            .title(Line::from(" [calendars] ".bold()).centered())
+            .title_bottom(instructions.centered())
+            .border_set(border::THICK);
+
+        if self.calendars.is_empty() {
+            let list = List::new([ListItem::new(" No calendars yet".italic())]).block(block);
+            StatefulWidget::render(list, area, buf, &mut self.state);
+            return;
+        }
+
+        let items = self.calendars.iter().map(|calendar| {
+            let color = calendar.color.as_deref().and_then(parse_hex_color).unwrap_or(Color::Reset);
+            let check: Span = if calendar.sync_enabled { " [x] ".into() } else { " [ ] ".dark_gray() };
+            ListItem::new(Line::from(vec![
+                check,
+                "● ".fg(color),
+                calendar.name.clone().into(),
+                format!(" ({})", calendar.access.as_str()).dark_gray(),
+            ]))
+        });
+        let list = List::new(items)
+            .block(block)
+            .highlight_style(Color::Indexed(17));
+        StatefulWidget::render(list, area, buf, &mut self.state);
+    }
+}
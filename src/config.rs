@@ -0,0 +1,306 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use chrono::Weekday;
+use dirs::{config_dir, home_dir};
+use ratatui::style::Color;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+    auth::AuthFlow,
+    error::UltimaError,
+    org::{expand_home, OrgMirrors},
+};
+
+/// Settings read from config.toml. Every key is optional, unknown keys are rejected so that typos
+/// don't go unnoticed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: String, // Paths may start with ~/
+    pub secret: String, // OAuth client secret downloaded from the Google Cloud console
+    pub token: String, // Where the OAuth token is cached
+    pub auth_flow: AuthFlow, // "browser", "device" or "manual", overridden by --auth-flow
+    pub calendars: Option<Vec<String>>, // IDs of the calendars to sync, all of them when unset
+    #[serde(deserialize_with = "seconds")]
+    pub sync_interval: Duration, // Given in seconds
+    #[serde(deserialize_with = "weekday")]
+    pub week_start: Weekday,
+    pub org: OrgConfig,
+    pub theme: Theme,
+    pub keybindings: Keybindings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            database: "~/.ultima/ultima.db".to_string(),
+            secret: "~/.ultima/secret.json".to_string(),
+            token: "~/.ultima/token.json".to_string(),
+            auth_flow: AuthFlow::default(),
+            calendars: None,
+            sync_interval: Duration::from_secs(5 * 60),
+            week_start: Weekday::Mon,
+            org: OrgConfig::default(),
+            theme: Theme::default(),
+            keybindings: Keybindings::default(),
+        }
+    }
+}
+
+/// The [org] table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrgConfig {
+    pub files: Vec<String>, // .org files to import, ~ allowed
+    pub mirrors: OrgMirrors,
+}
+
+/// Colours of the calendar grid and agenda, given as names ("yellow"), "#rrggbb" or a 256 colour index
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    #[serde(deserialize_with = "color")]
+    pub highlight: Color, // Selected row, column and agenda entry
+    #[serde(deserialize_with = "color")]
+    pub selected: Color, // Selected day
+    #[serde(deserialize_with = "color")]
+    pub today: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            highlight: Color::Indexed(17),
+            selected: Color::Yellow,
+            today: Color::Green,
+        }
+    }
+}
+
+/// Something a key of the main view can be bound to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Quit,
+    Back,
+    Down,
+    Up,
+    Forward,
+    BackYear,
+    ForwardYear,
+    Today,
+    NextEvent,
+    PreviousEvent,
+    Add,
+    Edit,
+    Delete,
+    Conflicts,
+    Calendars,
+    Sync,
+    View,
+}
+
+/// Keys of the main view, each a single character
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keybindings {
+    pub quit: char,
+    pub back: char, // Previous day
+    pub down: char, // Next month
+    pub up: char, // Previous month
+    pub forward: char, // Next day
+    pub back_year: char,
+    pub forward_year: char,
+    pub today: char,
+    pub next_event: char,
+    pub previous_event: char,
+    pub add: char,
+    pub edit: char,
+    pub delete: char,
+    pub conflicts: char,
+    pub calendars: char, // Pick the calendars to sync
+    pub sync: char, // Sync now instead of waiting for sync_interval
+    pub view: char, // Switch to the next of the year, week and month views
+}
+
+impl Default for Keybindings {
+    fn default() -> Self {
+        Self {
+            quit: 'q',
+            back: 'h',
+            down: 'j',
+            up: 'k',
+            forward: 'l',
+            back_year: 'u',
+            forward_year: 'd',
+            today: 't',
+            next_event: 'J',
+            previous_event: 'K',
+            add: 'a',
+            edit: 'e',
+            delete: 'x',
+            conflicts: 'c',
+            calendars: 'C',
+            sync: 's',
+            view: 'v',
+        }
+    }
+}
+
+impl Keybindings {
+    /// Every binding as (config key, action, key)
+    fn bindings(&self) -> [(&'static str, Action, char); 17] {
+        [
+            ("quit", Action::Quit, self.quit),
+            ("back", Action::Back, self.back),
+            ("down", Action::Down, self.down),
+            ("up", Action::Up, self.up),
+            ("forward", Action::Forward, self.forward),
+            ("back_year", Action::BackYear, self.back_year),
+            ("forward_year", Action::ForwardYear, self.forward_year),
+            ("today", Action::Today, self.today),
+            ("next_event", Action::NextEvent, self.next_event),
+            ("previous_event", Action::PreviousEvent, self.previous_event),
+            ("add", Action::Add, self.add),
+            ("edit", Action::Edit, self.edit),
+            ("delete", Action::Delete, self.delete),
+            ("conflicts", Action::Conflicts, self.conflicts),
+            ("calendars", Action::Calendars, self.calendars),
+            ("sync", Action::Sync, self.sync),
+            ("view", Action::View, self.view),
+        ]
+    }
+
+    /// Action bound to `key`, if any
+    pub fn action(&self, key: char) -> Option<Action> {
+        self.bindings().into_iter().find(|(_, _, bound)| *bound == key).map(|(_, action, _)| action)
+    }
+}
+
+impl Config {
+    pub fn database_path(&self) -> PathBuf {
+        expand_home(&self.database)
+    }
+
+    pub fn secret_path(&self) -> PathBuf {
+        expand_home(&self.secret)
+    }
+
+    pub fn token_path(&self) -> PathBuf {
+        expand_home(&self.token)
+    }
+
+    pub fn org_files(&self) -> Vec<PathBuf> {
+        self.org.files.iter().map(|file| expand_home(file)).collect()
+    }
+
+    /// Parse the contents of a config file. Errors name the offending key.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let config: Self = toml::from_str(text).map_err(|error| error.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks serde can't express: every key of the main view has to do only one thing
+    fn validate(&self) -> Result<(), String> {
+        let bindings = self.keybindings.bindings();
+        for (i, (name, _, key)) in bindings.iter().enumerate() {
+            if let Some((other, _, _)) = bindings[..i].iter().find(|(_, _, bound)| bound == key) {
+                return Err(format!("keybindings.{other} and keybindings.{name} are both bound to '{key}'"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ~/.ultima/config.toml, or the XDG config directory's ultima/config.toml when only that one exists
+pub fn config_path() -> Option<PathBuf> {
+    let home = home_dir().map(|home| home.join(".ultima/config.toml"));
+    let xdg = config_dir().map(|config| config.join("ultima/config.toml"));
+    match (home, xdg) {
+        (Some(home), _) if home.exists() => Some(home),
+        (_, Some(xdg)) if xdg.exists() => Some(xdg),
+        (home, _) => home,
+    }
+}
+
+/// Load the config file, falling back to the defaults when there is none
+pub fn load() -> Result<Config, UltimaError> {
+    let Some(path) = config_path().filter(|path| path.exists()) else {
+        return Ok(Config::default());
+    };
+    let text = fs::read_to_string(&path)
+        .map_err(|error| UltimaError::Config(format!("Unable to read {}: {error}", path.display())))?;
+    Config::parse(&text).map_err(|error| UltimaError::Config(format!("Invalid {}: {error}", path.display())))
+}
+
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(de::Error::custom("sync_interval must be at least 1 second")),
+        secs => Ok(Duration::from_secs(secs)),
+    }
+}
+
+fn weekday<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Weekday, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    text.parse().map_err(|_| de::Error::custom(format!("invalid week_start \"{text}\", expected a day such as \"monday\"")))
+}
+
+fn color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    text.parse().map_err(|_| de::Error::custom(format!("invalid colour \"{text}\", expected a name, \"#rrggbb\" or a number")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_section() {
+        let config = Config::parse(r##"
+            database = "/tmp/ultima.db"
+            auth_flow = "device"
+            calendars = ["primary", "work@example.com"]
+            sync_interval = 60
+            week_start = "sunday"
+
+            [org]
+            files = ["~/org/agenda.org"]
+            mirrors.tags = { work = "work@example.com" }
+
+            [theme]
+            highlight = "#334455"
+            today = "17"
+
+            [keybindings]
+            quit = "Q"
+        "##).unwrap();
+
+        assert_eq!(config.database_path(), PathBuf::from("/tmp/ultima.db"));
+        assert_eq!(config.token, Config::default().token);
+        assert_eq!(config.auth_flow, AuthFlow::Device);
+        assert_eq!(config.calendars.unwrap(), ["primary", "work@example.com"]);
+        assert_eq!(config.sync_interval, Duration::from_secs(60));
+        assert_eq!(config.week_start, Weekday::Sun);
+        assert_eq!(config.org.files, ["~/org/agenda.org"]);
+        assert_eq!(config.org.mirrors.tags["work"], "work@example.com");
+        assert_eq!(config.theme.highlight, Color::Rgb(0x33, 0x44, 0x55));
+        assert_eq!(config.theme.today, Color::Indexed(17));
+        assert_eq!(config.theme.selected, Color::Yellow);
+        assert_eq!(config.keybindings.action('Q'), Some(Action::Quit));
+        assert_eq!(config.keybindings.action('q'), None);
+    }
+
+    #[test]
+    fn errors_name_the_invalid_key() {
+        let error = |text: &str| Config::parse(text).unwrap_err();
+
+        assert!(error("sync_intervall = 60").contains("sync_intervall"));
+        assert!(error("[theme]\nbackground = \"red\"").contains("background"));
+        assert!(error("[org.mirrors]\nfile = {}").contains("file"));
+        assert!(error("week_start = \"someday\"").contains("week_start"));
+        assert!(error("auth_flow = \"oob\"").contains("auth_flow"));
+        assert!(error("sync_interval = 0").contains("sync_interval"));
+        assert!(error("[theme]\ntoday = \"blurple\"").contains("today"));
+        assert!(error("[keybindings]\nedit = \"a\"").contains("keybindings.add and keybindings.edit"));
+    }
+}
@@ -0,0 +1,201 @@
+use chrono::{Local, TimeDelta};
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Stylize},
+    symbols::border,
+    text::{Line, Span},
+    widgets::{Block, Paragraph, Widget, Wrap},
+};
+
+use crate::{database::SyncConflict, event::CalendarEvent};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Title,
+    Time,
+    Location,
+    Description,
+}
+
+const FIELDS: [Field; 4] = [Field::Title, Field::Time, Field::Location, Field::Description];
+
+/// What the TUI should do after the conflict view handled a key press
+pub enum ConflictAction {
+    None,
+    Close,
+    Resolve {
+        event: Box<CalendarEvent>,
+        remote_etag: String,
+        upload: bool, // Whether local values were kept and have to be pushed
+    },
+}
+
+/// Popup listing the fields of conflicted events that differ between the local and the remote
+/// version, letting the user keep either side per field
+pub struct ConflictView {
+    conflicts: Vec<SyncConflict>,
+    index: usize,
+    fields: Vec<Field>, // Differing fields of the current conflict
+    keep_local: Vec<bool>, // Chosen side of each differing field
+    focus: usize,
+}
+
+impl ConflictView {
+    /// Returns None if there are no conflicts to resolve
+    pub fn new(conflicts: Vec<SyncConflict>) -> Option<Self> {
+        if conflicts.is_empty() {
+            return None;
+        }
+
+        let mut view = Self {
+            conflicts,
+            index: 0,
+            fields: Vec::new(),
+            keep_local: Vec::new(),
+            focus: 0,
+        };
+        view.select(0);
+        Some(view)
+    }
+
+    /// Show the conflict at `index`, keeping the local side of every field by default
+    fn select(&mut self, index: usize) {
+        self.index = index;
+        let conflict = &self.conflicts[index];
+        self.fields = FIELDS.into_iter()
+            .filter(|field| value(&conflict.local, *field) != value(&conflict.remote, *field))
+            .collect();
+        self.keep_local = vec![true; self.fields.len()];
+        self.focus = 0;
+    }
+
+    pub fn handle_key_event(&mut self, key_event: KeyEvent) -> ConflictAction {
+        let count = self.conflicts.len();
+        match key_event.code {
+            KeyCode::Esc | KeyCode::Char('q') => return ConflictAction::Close,
+            KeyCode::Enter => return self.resolve(),
+            KeyCode::Tab => self.select((self.index + 1) % count),
+            KeyCode::BackTab => self.select((self.index + count - 1) % count),
+            KeyCode::Down | KeyCode::Char('j') if !self.fields.is_empty() => self.focus = (self.focus + 1) % self.fields.len(),
+            KeyCode::Up | KeyCode::Char('k') if !self.fields.is_empty() => self.focus = (self.focus + self.fields.len() - 1) % self.fields.len(),
+            KeyCode::Left | KeyCode::Char('h') => self.choose(true),
+            KeyCode::Right | KeyCode::Char('l') => self.choose(false),
+            KeyCode::Char(' ') => {
+                if let Some(keep_local) = self.keep_local.get_mut(self.focus) {
+                    *keep_local = !*keep_local;
+                }
+            }
+            KeyCode::Char('L') => self.keep_local.fill(true),
+            KeyCode::Char('R') => self.keep_local.fill(false),
+            _ => {}
+        }
+        ConflictAction::None
+    }
+
+    fn choose(&mut self, local: bool) {
+        if let Some(keep_local) = self.keep_local.get_mut(self.focus) {
+            *keep_local = local;
+        }
+    }
+
+    /// The remote event with the fields the user kept locally merged in. Attendees always come
+    /// from the remote version since they can't be edited locally.
+    fn resolve(&self) -> ConflictAction {
+        let SyncConflict { local, remote } = &self.conflicts[self.index];
+        let mut event = remote.clone();
+        for (field, _) in self.fields.iter().zip(&self.keep_local).filter(|(_, keep_local)| **keep_local) {
+            match field {
+                Field::Title => event.title = local.title.clone(),
+                Field::Time => (event.start_time, event.end_time, event.all_day) = (local.start_time, local.end_time, local.all_day),
+                Field::Location => event.location = local.location.clone(),
+                Field::Description => event.description = local.description.clone(),
+            }
+        }
+        ConflictAction::Resolve {
+            event: Box::new(event),
+            remote_etag: remote.etag.clone(),
+            upload: self.keep_local.contains(&true),
+        }
+    }
+}
+
+fn label(field: Field) -> &'static str {
+    match field {
+        Field::Title => "Title",
+        Field::Time => "Time",
+        Field::Location => "Location",
+        Field::Description => "Description",
+    }
+}
+
+/// Text shown for a field, also used to tell whether both versions differ
+fn value(event: &CalendarEvent, field: Field) -> String {
+    match field {
+        Field::Title => event.title.clone(),
+        Field::Time if event.all_day => {
+            let last = (event.end_time - TimeDelta::days(1)).max(event.start_time);
+            format!("{} – {} (all day)", event.start_time.format("%Y-%m-%d"), last.format("%Y-%m-%d"))
+        }
+        Field::Time => format!("{} – {}",
+            event.start_time.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            event.end_time.with_timezone(&Local).format("%Y-%m-%d %H:%M")),
+        Field::Location => event.location.clone().unwrap_or_default(),
+        Field::Description => event.description.clone().unwrap_or_default(),
+    }
+}
+
+impl Widget for &ConflictView {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let conflict = &self.conflicts[self.index];
+        let title = format!(" [conflict {}/{}] {} ", self.index + 1, self.conflicts.len(), conflict.local.title);
+        let instructions = Line::from(vec![
+            " Local/remote ".into(),
+            "<←/→>".blue().bold(),
+            " All local ".into(),
+            "<L>".blue().bold(),
+            " All remote ".into(),
+            "<R>".blue().bold(),
+            " Next conflict ".into(),
+            "<Tab>".blue().bold(),
+            " Resolve ".into(),
+            "<Enter>".blue().bold(),
+            " Close ".into(),
+            "<Esc>".blue().bold(),
+            " ".into(),
+        ]);
+        let block = Block::bordered()
+            .title(Line::from(title.bold()).centered())
+            .title_bottom(instructions.centered())
+            .border_set(border::THICK);
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        let [header_area, fields_area] = Layout::vertical([Constraint::Length(2), Constraint::Min(0)]).areas(inner);
+        let columns = Layout::horizontal([Constraint::Length(13), Constraint::Fill(1), Constraint::Fill(1)]);
+        let [_, local_header, remote_header] = columns.areas(header_area);
+        Paragraph::new(" Local".bold()).render(local_header, buf);
+        Paragraph::new(" Remote".bold()).render(remote_header, buf);
+
+        if self.fields.is_empty() {
+            Paragraph::new(" Only the attendees differ, the remote version will be kept".italic()).render(fields_area, buf);
+            return;
+        }
+
+        let rows = Layout::vertical(self.fields.iter().map(|_| Constraint::Fill(1))).split(fields_area);
+        for (i, (field, keep_local)) in self.fields.iter().zip(&self.keep_local).enumerate() {
+            let [label_area, local_area, remote_area] = columns.areas(rows[i]);
+            let side = |event: &CalendarEvent, chosen: bool| {
+                let text: Span = format!(" {}", value(event, *field)).into();
+                let paragraph = Paragraph::new(if chosen { text.bold().green() } else { text.dark_gray() })
+                    .wrap(Wrap { trim: false });
+                if i == self.focus { paragraph.bg(Color::Indexed(17)) } else { paragraph }
+            };
+            let label = Paragraph::new(format!(" {:<12}", label(*field)).bold());
+            if i == self.focus { label.bg(Color::Indexed(17)) } else { label }.render(label_area, buf);
+            side(&conflict.local, *keep_local).render(local_area, buf);
+            side(&conflict.remote, !*keep_local).render(remote_area, buf);
+        }
+    }
+}
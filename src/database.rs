@@ -1,9 +1,65 @@
-use std::path::Path;
+use std::{collections::HashMap, path::Path};
 
-use chrono::Local;
-use rusqlite::{params, Connection, Error};
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, Utc};
+use rusqlite::{params, params_from_iter, Connection, Error, OptionalExtension, Row, ToSql, Transaction};
 
-use crate::{event::{AccessRole, CalendarEvent, GcalCalendar}, google_calendar_api::GoogleCalendarAPI};
+use crate::{
+    event::{local_day_start, AccessRole, CalendarEvent, GcalCalendar, SourceType},
+    org::{mirror_event_id, OrgEvent, OrgLocation},
+    recurrence::occurrences,
+};
+
+/// Columns selected by every event query, in the order expected by `event_from_row`
+const EVENT_COLUMNS: &str = "e.title, e.description, e.location, e.start_time, e.end_time, s.gcal_etag, \
+    e.event_id, e.calendar_id, e.source_type, s.needs_upload, e.attendees, s.upload_error, e.all_day, \
+    e.recurrence, e.recurring_event_id, e.original_start_time, e.time_zone";
+
+/// Columns added to sql/schema.sql after its first release, as (table, column, declaration).
+/// `CREATE TABLE IF NOT EXISTS` leaves older databases alone, so these are added on open.
+const COLUMN_MIGRATIONS: &[(&str, &str, &str)] = &[
+    ("events", "attendees", "TEXT"),
+    ("sync_metadata", "upload_attempts", "INTEGER DEFAULT 0"),
+    ("sync_metadata", "upload_error", "TEXT"),
+    ("sync_metadata", "upload_retry_at", "INTEGER"),
+    ("calendars", "sync_token", "TEXT"),
+    ("events", "all_day", "BOOLEAN DEFAULT FALSE"),
+    ("events", "recurrence", "TEXT"),
+    ("events", "recurring_event_id", "TEXT"),
+    ("events", "original_start_time", "INTEGER"),
+    ("events", "time_zone", "TEXT"),
+    ("sync_metadata", "gcal_event_id", "TEXT"),
+    ("sync_metadata", "remote_version", "TEXT"),
+];
+
+/// Delay in seconds before retrying a failed upload, doubled after every further failure
+const UPLOAD_BASE_BACKOFF: i64 = 5;
+const UPLOAD_MAX_BACKOFF: i64 = 60 * 60;
+
+/// A locally changed event waiting to be pushed to its remote calendar
+#[derive(Debug, Clone)]
+pub struct PendingUpload {
+    pub local_id: i64,
+    pub event: CalendarEvent,
+    pub deleted: bool,
+    pub gcal_synced: bool, // Whether the event already exists remotely
+    pub target_calendar_id: Option<String>, // Calendar an org event is mirrored to
+}
+
+/// Result of writing a remote item into the local cache
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncOutcome {
+    Created,
+    Updated,
+    Unchanged,
+    Conflict, // Changed remotely while local changes were waiting to be uploaded
+}
+
+/// An event changed both locally and remotely since it was last synced
+#[derive(Debug, Clone)]
+pub struct SyncConflict {
+    pub local: CalendarEvent,
+    pub remote: CalendarEvent, // Its etag is the remote one
+}
 
 pub struct Database {
     db: Connection
@@ -17,66 +73,1287 @@ impl Database {
 
         // STEP 1: Configure database settings (individual execute calls)
         db.execute("PRAGMA foreign_keys = ON", [])?;           // Data integrity
-        db.execute("PRAGMA journal_mode = WAL", [])?;          // Can't change in transaction!
+        db.pragma_update(None, "journal_mode", "WAL")?;        // Can't change in transaction! Returns a row, so no execute
         db.execute("PRAGMA synchronous = NORMAL", [])?;        // Speed/safety balance
         db.execute("PRAGMA cache_size = -64000", [])?;         // 64MB cache
         db.execute("PRAGMA temp_store = MEMORY", [])?;         // Fast temp operations
         db.execute_batch(include_str!("../sql/schema.sql"))?;
+        Self::migrate(&db)?;
         Ok(Self {
             db
         })
     }
 
-    pub fn sync_calendar(&mut self, calendar: &mut GcalCalendar) -> Result<(), rusqlite::Error> {
+    fn migrate(db: &Connection) -> Result<(), Error> {
+        for (table, column, declaration) in COLUMN_MIGRATIONS {
+            let exists: bool = db.query_row(
+                "SELECT EXISTS(SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+                params![table, column],
+                |row| row.get(0))?;
+            if !exists {
+                db.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {declaration}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Upsert a remote calendar, keyed on calendar_id. The row is left untouched if none of the
+    /// calendar's fields changed. sync_enabled is only taken from `calendar` when it is inserted,
+    /// afterwards it belongs to the user, see `set_calendar_enabled`.
+    pub fn sync_calendar(&mut self, calendar: &GcalCalendar) -> Result<SyncOutcome, rusqlite::Error> {
         let tx = self.db.transaction()?;
 
-        if tx.query_row(
-            "SELECT COUNT(1) FROM calendars WHERE calendar_id = ?1",
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM calendars WHERE calendar_id = ?1)",
             params![&calendar.id],
-            |row| Ok(row.get(0) == Ok(1)))? {
-            //TODO just accept remote changes tbh
-            if let Some(color) = &calendar.color {
-                tx.execute("UPDATE calendars SET display_color = ?1 WHERE calendar_id = ?2", params![color, &calendar.id])?;
+            |row| row.get(0))?;
+
+        let written = tx.execute(
+            "INSERT INTO calendars (calendar_id, display_name, color, access_role, sync_enabled, last_sync_time, etag)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT(calendar_id) DO UPDATE SET
+                display_name = excluded.display_name,
+                color = excluded.color,
+                access_role = excluded.access_role,
+                etag = excluded.etag
+             WHERE display_name IS NOT excluded.display_name
+                OR color IS NOT excluded.color
+                OR access_role IS NOT excluded.access_role
+                OR etag IS NOT excluded.etag",
+            params![
+                &calendar.id,
+                &calendar.name,
+                &calendar.color,
+                calendar.access.as_str(),
+                calendar.sync_enabled,
+                calendar.last_sync_time.map(|time| time.timestamp()),
+                &calendar.etag,
+            ])?;
+
+        tx.commit()?;
+        Ok(match (exists, written) {
+            (false, _) => SyncOutcome::Created,
+            (true, 0) => SyncOutcome::Unchanged,
+            (true, _) => SyncOutcome::Updated,
+        })
+    }
+
+    /// Upsert a remote event and its sync metadata, keyed on (event_id, calendar_id).
+    /// Nothing is written if the stored etag already matches the event's etag, or if it mirrors an
+    /// org event. If the event also has local changes waiting to be uploaded, neither side is
+    /// overwritten: the remote version is kept aside and the event is marked as conflicted until
+    /// `resolve_conflict`.
+    pub fn sync_event(&mut self, event: &CalendarEvent) -> Result<SyncOutcome, rusqlite::Error> {
+        let tx = self.db.transaction()?;
+
+        let mirror: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sync_metadata WHERE gcal_event_id = ?1 AND target_calendar_id = ?2)",
+            params![&event.event_id, &event.calendar_id],
+            |row| row.get(0))?;
+        if mirror {
+            return Ok(SyncOutcome::Unchanged);
+        }
+
+        let stored: Option<(i64, Option<String>, bool, bool)> = tx.query_row(
+            "SELECT e.local_id, s.gcal_etag, COALESCE(s.needs_upload, FALSE), e.deleted FROM events e
+             LEFT JOIN sync_metadata s ON s.local_id = e.local_id
+             WHERE e.event_id = ?1 AND e.calendar_id = ?2",
+            params![&event.event_id, &event.calendar_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).optional()?;
+        let outcome = match stored {
+            None => SyncOutcome::Created,
+            Some((_, etag, _, _)) if etag.as_deref() == Some(event.etag.as_str()) => return Ok(SyncOutcome::Unchanged),
+            // A local deletion always wins, edits of an event that was uploaded before conflict
+            Some((local_id, Some(etag), true, false)) if !etag.is_empty() => {
+                tx.execute(
+                    "UPDATE sync_metadata SET sync_conflict = TRUE, remote_version = ?2 WHERE local_id = ?1",
+                    params![local_id, remote_version_json(event)])?;
+                tx.commit()?;
+                return Ok(SyncOutcome::Conflict);
             }
-            tx.execute("UPDATE calendars SET display_name = ?1 WHERE calendar_id = ?2", params![&calendar.name, &calendar.id])?;
-            let access = match &calendar.access {
-                AccessRole::Owner => { "owner" }
-                AccessRole::Reader => { "reader" }
-                AccessRole::Writer => { "writer" }
-                AccessRole::FreeBusyReader => { "freeBusyReader" }
-            };
-            tx.execute("UPDATE calendars SET access_role = ?1 WHERE calendar_id = ?2", params![access, &calendar.id])?;
-            tx.execute("UPDATE calendars SET sync_enabled = ?1 WHERE calendar_id = ?2", params![&calendar.sync_enabled, &calendar.id])?;
-            let last_sync_time = Local::now().timestamp();
-            tx.execute("UPDATE calendars SET last_sync_time = ?1 WHERE calendar_id = ?2", params![last_sync_time, &calendar.id])?;
-            if let Some(etag) = &calendar.etag {
-                tx.execute("UPDATE calendars SET etag = ?1 WHERE calendar_id = ?2", params![etag, &calendar.id])?;
+            Some((_, _, true, _)) => return Ok(SyncOutcome::Unchanged),
+            Some(_) => SyncOutcome::Updated,
+        };
+
+        let local_id: i64 = tx.query_row(
+            "INSERT INTO events (event_id, calendar_id, source_type, title, description, location, attendees, start_time, end_time, all_day,
+                recurrence, recurring_event_id, original_start_time, time_zone, deleted)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, FALSE)
+             ON CONFLICT(event_id, calendar_id) DO UPDATE SET
+                source_type = excluded.source_type,
+                title = excluded.title,
+                description = excluded.description,
+                location = excluded.location,
+                attendees = excluded.attendees,
+                start_time = excluded.start_time,
+                end_time = excluded.end_time,
+                all_day = excluded.all_day,
+                recurrence = excluded.recurrence,
+                recurring_event_id = excluded.recurring_event_id,
+                original_start_time = excluded.original_start_time,
+                time_zone = excluded.time_zone,
+                deleted = FALSE
+             RETURNING local_id",
+            params![
+                &event.event_id,
+                &event.calendar_id,
+                event.source_type.as_str(),
+                &event.title,
+                &event.description,
+                &event.location,
+                attendees_json(&event.attendees),
+                event.start_time.timestamp(),
+                event.end_time.timestamp(),
+                event.all_day,
+                recurrence_text(&event.recurrence),
+                &event.recurring_event_id,
+                event.original_start_time.map(|time| time.timestamp()),
+                &event.time_zone,
+            ],
+            |row| row.get(0))?;
+
+        let last_sync_time = Local::now().timestamp();
+        tx.execute(
+            "INSERT INTO sync_metadata (local_id, source_type, gcal_etag, gcal_synced, last_sync_time)
+             VALUES (?1, ?2, ?3, TRUE, ?4)
+             ON CONFLICT(local_id) DO UPDATE SET
+                gcal_etag = excluded.gcal_etag,
+                gcal_synced = TRUE,
+                last_sync_time = excluded.last_sync_time",
+            params![local_id, event.source_type.as_str(), &event.etag, last_sync_time])?;
+
+        tx.commit()?;
+        Ok(outcome)
+    }
+
+    /// Content hash of an org file when it was last imported, None if it never was
+    pub fn org_file_hash(&self, path: &str) -> Result<Option<String>, rusqlite::Error> {
+        let hash = self.db.query_row(
+            "SELECT content_hash FROM org_files WHERE path = ?1",
+            params![path],
+            |row| row.get(0)).optional()?;
+        Ok(hash)
+    }
+
+    /// File and lines an org event was imported from
+    pub fn org_location(&self, event_id: &str, calendar_id: &str) -> Result<Option<OrgLocation>, rusqlite::Error> {
+        self.db.query_row(
+            "SELECT s.org_file_path, s.org_line_start, s.org_line_end, s.org_content_hash
+             FROM events e JOIN sync_metadata s ON s.local_id = e.local_id
+             WHERE e.event_id = ?1 AND e.calendar_id = ?2 AND s.org_file_path IS NOT NULL",
+            params![event_id, calendar_id],
+            |row| Ok(OrgLocation {
+                path: row.get(0)?,
+                line_start: row.get(1)?,
+                line_end: row.get(2)?,
+                content_hash: row.get(3)?,
+            })).optional()
+    }
+
+    /// Mirror calendar of each event imported from an org file, keyed on event ID
+    pub fn org_targets(&self, path: &str) -> Result<HashMap<String, Option<String>>, rusqlite::Error> {
+        let mut stmt = self.db.prepare(
+            "SELECT e.event_id, s.target_calendar_id FROM events e JOIN sync_metadata s ON s.local_id = e.local_id
+             WHERE s.org_file_path = ?1")?;
+        let targets = stmt.query_map(params![path], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(targets)
+    }
+
+    /// Upsert the events parsed from an org file, recording their line ranges and the file's
+    /// content hash, and drop the events that are no longer in the file. Events with a target
+    /// calendar are queued for upload when they change, and the mirrors of events that left the
+    /// file or moved to another calendar are queued for deletion.
+    pub fn sync_org_file(&mut self, path: &str, content_hash: &str, events: &[OrgEvent]) -> Result<(), rusqlite::Error> {
+        let tx = self.db.transaction()?;
+
+        // Mirror event ID is only set once the mirror was created
+        let stored: Vec<(i64, String, Option<String>, Option<String>)> = tx.prepare(
+            "SELECT e.local_id, e.event_id, s.target_calendar_id, CASE WHEN s.gcal_synced THEN s.gcal_event_id END
+             FROM events e LEFT JOIN sync_metadata s ON s.local_id = e.local_id
+             WHERE e.calendar_id = ?1 AND e.source_type = 'orgmode'")?
+            .query_map(params![path], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+            .collect::<Result<_, _>>()?;
+        for (local_id, event_id, target, gcal_event_id) in stored {
+            let current = events.iter().find(|org| org.event.event_id == event_id);
+            let moved = current.is_none_or(|org| org.target_calendar_id != target);
+            if moved && let (Some(target), Some(gcal_event_id)) = (target, gcal_event_id) {
+                queue_mirror_deletion(&tx, &gcal_event_id, &target)?;
+            }
+            if current.is_none() {
+                tx.execute("DELETE FROM events WHERE local_id = ?1", params![local_id])?;
             }
         }
 
-        if let Some(color) = &calendar.color {
-            tx.execute("INSERT INTO calendars SET display_color = ?1 WHERE calendar_id = ?2", params![color, &calendar.id])?;
-        }
-        tx.execute("UPDATE calendars SET display_name = ?1 WHERE calendar_id = ?2", params![&calendar.name, &calendar.id])?;
-        let access = match &calendar.access {
-            AccessRole::Owner => { "owner" }
-            AccessRole::Reader => { "reader" }
-            AccessRole::Writer => { "writer" }
-            AccessRole::FreeBusyReader => { "freeBusyReader" }
-        };
-        tx.execute("UPDATE calendars SET access_role = ?1 WHERE calendar_id = ?2", params![access, &calendar.id])?;
-        tx.execute("UPDATE calendars SET sync_enabled = ?1 WHERE calendar_id = ?2", params![&calendar.sync_enabled, &calendar.id])?;
         let last_sync_time = Local::now().timestamp();
-        tx.execute("UPDATE calendars SET last_sync_time = ?1 WHERE calendar_id = ?2", params![last_sync_time, &calendar.id])?;
-        if let Some(etag) = &calendar.etag {
-            tx.execute("UPDATE calendars SET etag = ?1 WHERE calendar_id = ?2", params![etag, &calendar.id])?;
+        for org in events {
+            let event = &org.event;
+            // (fields changed, previous target) of the stored event
+            let stored: Option<(bool, Option<String>)> = tx.query_row(
+                "SELECT e.title IS NOT ?3 OR e.description IS NOT ?4 OR e.start_time IS NOT ?5
+                    OR e.end_time IS NOT ?6 OR e.all_day IS NOT ?7, s.target_calendar_id
+                 FROM events e LEFT JOIN sync_metadata s ON s.local_id = e.local_id
+                 WHERE e.event_id = ?1 AND e.calendar_id = ?2",
+                params![
+                    &event.event_id,
+                    path,
+                    &event.title,
+                    &event.description,
+                    event.start_time.timestamp(),
+                    event.end_time.timestamp(),
+                    event.all_day,
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+            let retargeted = stored.as_ref().is_some_and(|(_, target)| *target != org.target_calendar_id);
+            let changed = stored.is_none_or(|(changed, _)| changed) || retargeted;
+            let needs_upload = changed && org.target_calendar_id.is_some();
+            let gcal_event_id = org.target_calendar_id.as_ref().map(|target| mirror_event_id(target, &event.event_id));
+
+            let local_id: i64 = tx.query_row(
+                "INSERT INTO events (event_id, calendar_id, source_type, title, description, start_time, end_time, all_day, deleted)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, FALSE)
+                 ON CONFLICT(event_id, calendar_id) DO UPDATE SET
+                    title = excluded.title,
+                    description = excluded.description,
+                    start_time = excluded.start_time,
+                    end_time = excluded.end_time,
+                    all_day = excluded.all_day,
+                    deleted = FALSE
+                 RETURNING local_id",
+                params![
+                    &event.event_id,
+                    path,
+                    event.source_type.as_str(),
+                    &event.title,
+                    &event.description,
+                    event.start_time.timestamp(),
+                    event.end_time.timestamp(),
+                    event.all_day,
+                ],
+                |row| row.get(0))?;
+            tx.execute(
+                "INSERT INTO sync_metadata (local_id, source_type, org_file_path, org_line_start, org_line_end, org_content_hash, last_sync_time,
+                    target_calendar_id, gcal_event_id, needs_upload)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                 ON CONFLICT(local_id) DO UPDATE SET
+                    org_file_path = excluded.org_file_path,
+                    org_line_start = excluded.org_line_start,
+                    org_line_end = excluded.org_line_end,
+                    org_content_hash = excluded.org_content_hash,
+                    last_sync_time = excluded.last_sync_time,
+                    target_calendar_id = excluded.target_calendar_id,
+                    gcal_event_id = excluded.gcal_event_id,
+                    gcal_synced = gcal_synced AND NOT ?11,
+                    needs_upload = needs_upload OR excluded.needs_upload,
+                    upload_attempts = CASE WHEN excluded.needs_upload THEN 0 ELSE upload_attempts END,
+                    upload_error = CASE WHEN excluded.needs_upload THEN NULL ELSE upload_error END,
+                    upload_retry_at = CASE WHEN excluded.needs_upload THEN NULL ELSE upload_retry_at END",
+                params![
+                    local_id,
+                    event.source_type.as_str(),
+                    path,
+                    org.line_start,
+                    org.line_end,
+                    content_hash,
+                    last_sync_time,
+                    &org.target_calendar_id,
+                    gcal_event_id,
+                    needs_upload,
+                    retargeted,
+                ])?;
         }
 
-        //TODO insert into, probably a more graceful way to do this than the above
+        tx.execute(
+            "INSERT INTO org_files (path, content_hash) VALUES (?1, ?2)
+             ON CONFLICT(path) DO UPDATE SET content_hash = excluded.content_hash",
+            params![path, content_hash])?;
+        tx.commit()
+    }
+
+    /// All events overlapping the local dates `start..=end`, ordered by start time.
+    /// Soft-deleted events are excluded.
+    pub fn events_in_range(&self, start: NaiveDate, end: NaiveDate) -> Result<Vec<CalendarEvent>, rusqlite::Error> {
+        self.query_events(start, end, None)
+    }
+
+    /// All events overlapping the given local date
+    pub fn events_on_day(&self, day: NaiveDate) -> Result<Vec<CalendarEvent>, rusqlite::Error> {
+        self.query_events(day, day, None)
+    }
+
+    /// Same as `events_in_range`, restricted to the given calendars
+    pub fn events_for_calendars(&self, calendar_ids: &[&str], start: NaiveDate, end: NaiveDate) -> Result<Vec<CalendarEvent>, rusqlite::Error> {
+        if calendar_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.query_events(start, end, Some(calendar_ids))
+    }
+
+    /// Sync token to pass to the next incremental events.list of this calendar
+    pub fn calendar_sync_token(&self, calendar_id: &str) -> Result<Option<String>, rusqlite::Error> {
+        let token = self.db.query_row(
+            "SELECT sync_token FROM calendars WHERE calendar_id = ?1",
+            params![calendar_id],
+            |row| row.get(0)).optional()?;
+        Ok(token.flatten())
+    }
+
+    /// Store the token to continue from after a calendar's events were synced, which also makes
+    /// now its last sync time
+    pub fn set_calendar_sync_token(&mut self, calendar_id: &str, sync_token: Option<&str>) -> Result<(), rusqlite::Error> {
+        self.db.execute(
+            "UPDATE calendars SET sync_token = ?2, last_sync_time = ?3 WHERE calendar_id = ?1",
+            params![calendar_id, sync_token, Local::now().timestamp()])?;
         Ok(())
     }
 
-    pub fn sync_event(&mut self, event: &mut CalendarEvent) {
-        todo!()
+    /// Prepare a calendar for a full resync after its sync token expired: the token is dropped
+    /// along with every cached event that has no local changes waiting to be uploaded.
+    pub fn reset_calendar_sync(&mut self, calendar_id: &str) -> Result<(), rusqlite::Error> {
+        let tx = self.db.transaction()?;
+        tx.execute("UPDATE calendars SET sync_token = NULL WHERE calendar_id = ?1", params![calendar_id])?;
+        tx.execute(
+            "DELETE FROM events WHERE calendar_id = ?1 AND local_id NOT IN
+                (SELECT local_id FROM sync_metadata WHERE needs_upload = TRUE)",
+            params![calendar_id])?;
+        tx.commit()
+    }
+
+    /// Turn syncing of a calendar on or off. Disabling it purges its cached events and its sync
+    /// token so that enabling it again starts with a full sync. Local changes waiting to be
+    /// uploaded are kept, and held back until the calendar is enabled again.
+    pub fn set_calendar_enabled(&mut self, calendar_id: &str, enabled: bool) -> Result<(), rusqlite::Error> {
+        let tx = self.db.transaction()?;
+        tx.execute("UPDATE calendars SET sync_enabled = ?2 WHERE calendar_id = ?1", params![calendar_id, enabled])?;
+        if !enabled {
+            tx.execute("UPDATE calendars SET sync_token = NULL, last_sync_time = NULL WHERE calendar_id = ?1", params![calendar_id])?;
+            tx.execute(
+                "DELETE FROM events WHERE calendar_id = ?1 AND local_id NOT IN
+                    (SELECT local_id FROM sync_metadata WHERE needs_upload = TRUE)",
+                params![calendar_id])?;
+        }
+        tx.commit()
+    }
+
+    /// Disable every calendar missing from `calendar_ids`, the calendars listed in the config,
+    /// purging their cached events like `set_calendar_enabled`
+    pub fn disable_calendars_except(&mut self, calendar_ids: &[String]) -> Result<(), rusqlite::Error> {
+        for calendar in self.calendars()? {
+            if calendar.sync_enabled && !calendar_ids.contains(&calendar.id) {
+                self.set_calendar_enabled(&calendar.id, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop an event that was cancelled remotely, unless it has local changes waiting to be uploaded.
+    /// Cancelling a recurring series also drops its modified instances.
+    pub fn remove_cancelled_event(&mut self, event_id: &str, calendar_id: &str) -> Result<(), rusqlite::Error> {
+        self.db.execute(
+            "DELETE FROM events WHERE (event_id = ?1 OR recurring_event_id = ?1) AND calendar_id = ?2 AND local_id NOT IN
+                (SELECT local_id FROM sync_metadata WHERE needs_upload = TRUE)",
+            params![event_id, calendar_id])?;
+        Ok(())
+    }
+
+    /// Record an occurrence of a recurring series that was cancelled remotely, so that expanding
+    /// the series skips it. Local changes waiting to be uploaded are left alone.
+    pub fn cancel_occurrence(&mut self, event_id: &str, calendar_id: &str, recurring_event_id: &str, original_start_time: DateTime<Utc>) -> Result<(), rusqlite::Error> {
+        let tx = self.db.transaction()?;
+
+        let pending: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM events e JOIN sync_metadata s ON s.local_id = e.local_id
+             WHERE e.event_id = ?1 AND e.calendar_id = ?2 AND s.needs_upload = TRUE)",
+            params![event_id, calendar_id],
+            |row| row.get(0))?;
+        if pending {
+            return Ok(());
+        }
+
+        let mut occurrence = CalendarEvent::new_local(String::new(), original_start_time, original_start_time, calendar_id.to_string());
+        occurrence.event_id = event_id.to_string();
+        occurrence.recurring_event_id = Some(recurring_event_id.to_string());
+        occurrence.original_start_time = Some(original_start_time);
+        let local_id = insert_cancelled_occurrence(&tx, &occurrence)?;
+        tx.execute(
+            "INSERT INTO sync_metadata (local_id, source_type, gcal_synced) VALUES (?1, ?2, TRUE)
+             ON CONFLICT(local_id) DO UPDATE SET gcal_synced = TRUE",
+            params![local_id, occurrence.source_type.as_str()])?;
+
+        tx.commit()
+    }
+
+    /// Write an event created or edited in the TUI and flag it for upload. Editing an occurrence of
+    /// a recurring series stores it as a modified instance of that series. A sync conflict stays
+    /// until it is resolved, see `resolve_conflict`.
+    pub fn save_local_event(&mut self, event: &CalendarEvent) -> Result<(), rusqlite::Error> {
+        let tx = self.db.transaction()?;
+
+        let local_id: i64 = tx.query_row(
+            "INSERT INTO events (event_id, calendar_id, source_type, title, description, location, attendees, start_time, end_time, all_day,
+                recurrence, recurring_event_id, original_start_time, time_zone, deleted)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, FALSE)
+             ON CONFLICT(event_id, calendar_id) DO UPDATE SET
+                title = excluded.title,
+                description = excluded.description,
+                location = excluded.location,
+                start_time = excluded.start_time,
+                end_time = excluded.end_time,
+                all_day = excluded.all_day
+             RETURNING local_id",
+            params![
+                &event.event_id,
+                &event.calendar_id,
+                event.source_type.as_str(),
+                &event.title,
+                &event.description,
+                &event.location,
+                attendees_json(&event.attendees),
+                event.start_time.timestamp(),
+                event.end_time.timestamp(),
+                event.all_day,
+                recurrence_text(&event.recurrence),
+                &event.recurring_event_id,
+                event.original_start_time.map(|time| time.timestamp()),
+                &event.time_zone,
+            ],
+            |row| row.get(0))?;
+
+        // Occurrences of a remote series already exist remotely, so they are patched rather than inserted
+        tx.execute(
+            "INSERT INTO sync_metadata (local_id, source_type, needs_upload, gcal_synced) VALUES (?1, ?2, TRUE, ?3)
+             ON CONFLICT(local_id) DO UPDATE SET
+                needs_upload = TRUE,
+                upload_attempts = 0,
+                upload_error = NULL,
+                upload_retry_at = NULL",
+            params![local_id, event.source_type.as_str(), event.recurring_event_id.is_some()])?;
+
+        tx.commit()
+    }
+
+    /// Soft delete an event and flag it for upload. The row is only removed once the deletion
+    /// has been pushed, see `purge_event`. Deleting an occurrence of a recurring series stores a
+    /// cancelled instance that hides it from the series.
+    pub fn delete_local_event(&mut self, event: &CalendarEvent) -> Result<(), rusqlite::Error> {
+        let tx = self.db.transaction()?;
+
+        let mut local_id: Option<i64> = tx.query_row(
+            "UPDATE events SET deleted = TRUE WHERE event_id = ?1 AND calendar_id = ?2 RETURNING local_id",
+            params![&event.event_id, &event.calendar_id],
+            |row| row.get(0)).optional()?;
+        if local_id.is_none() && event.recurring_event_id.is_some() {
+            local_id = Some(insert_cancelled_occurrence(&tx, event)?);
+        }
+        if let Some(local_id) = local_id {
+            tx.execute(
+                "INSERT INTO sync_metadata (local_id, source_type, needs_upload, gcal_synced)
+                 SELECT local_id, source_type, TRUE, recurring_event_id IS NOT NULL FROM events WHERE local_id = ?1
+                 ON CONFLICT(local_id) DO UPDATE SET
+                    needs_upload = TRUE,
+                    upload_attempts = 0,
+                    upload_error = NULL,
+                    upload_retry_at = NULL",
+                params![local_id])?;
+        }
+
+        tx.commit()
+    }
+
+    /// Events changed both locally and remotely, waiting for the user to pick what to keep
+    pub fn conflicts(&self) -> Result<Vec<SyncConflict>, rusqlite::Error> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {EVENT_COLUMNS}, s.remote_version FROM sync_metadata s
+             JOIN events e ON e.local_id = s.local_id
+             WHERE s.sync_conflict = TRUE AND e.deleted = FALSE
+             ORDER BY e.start_time"))?;
+        let conflicts = stmt.query_map([], |row| {
+            let local = event_from_row(row)?;
+            let remote = row.get::<_, Option<String>>(17)?
+                .and_then(|json| serde_json::from_str(&json).ok())
+                .map(|remote| remote_from_json(&local, &remote))
+                .unwrap_or_else(|| local.clone());
+            Ok(SyncConflict { local, remote })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(conflicts)
+    }
+
+    /// Settle a conflict with the version the user put together. `remote_etag` is the etag of the
+    /// remote version it was resolved against, the event is only uploaded again when `upload` is set,
+    /// i.e. when some local changes were kept.
+    pub fn resolve_conflict(&mut self, event: &CalendarEvent, remote_etag: &str, upload: bool) -> Result<(), rusqlite::Error> {
+        let tx = self.db.transaction()?;
+
+        let local_id: i64 = tx.query_row(
+            "UPDATE events SET
+                title = ?3,
+                description = ?4,
+                location = ?5,
+                attendees = ?6,
+                start_time = ?7,
+                end_time = ?8,
+                all_day = ?9
+             WHERE event_id = ?1 AND calendar_id = ?2
+             RETURNING local_id",
+            params![
+                &event.event_id,
+                &event.calendar_id,
+                &event.title,
+                &event.description,
+                &event.location,
+                attendees_json(&event.attendees),
+                event.start_time.timestamp(),
+                event.end_time.timestamp(),
+                event.all_day,
+            ],
+            |row| row.get(0))?;
+        tx.execute(
+            "UPDATE sync_metadata SET
+                sync_conflict = FALSE,
+                remote_version = NULL,
+                gcal_etag = ?2,
+                needs_upload = ?3,
+                last_sync_time = ?4,
+                upload_attempts = 0,
+                upload_error = NULL,
+                upload_retry_at = NULL
+             WHERE local_id = ?1",
+            params![local_id, remote_etag, upload, Local::now().timestamp()])?;
+
+        tx.commit()
+    }
+
+    /// Events with local changes that still have to be pushed, including soft-deleted ones.
+    /// Uploads that failed recently are left out until their backoff has passed, and so are events
+    /// of, or org events mirrored to, a calendar that is disabled in the calendar list.
+    pub fn pending_uploads(&self) -> Result<Vec<PendingUpload>, rusqlite::Error> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {EVENT_COLUMNS}, e.local_id, e.deleted, s.gcal_synced, s.target_calendar_id
+             FROM sync_metadata s INDEXED BY idx_sync_needs_upload
+             JOIN events e ON e.local_id = s.local_id
+             WHERE s.needs_upload = TRUE AND (s.upload_retry_at IS NULL OR s.upload_retry_at <= ?1)
+                AND s.sync_conflict IS NOT TRUE
+                AND NOT EXISTS(SELECT 1 FROM calendars c
+                    WHERE c.calendar_id IN (e.calendar_id, s.target_calendar_id) AND c.sync_enabled = FALSE)
+             ORDER BY e.modified_at"))?;
+        let pending = stmt.query_map(params![Local::now().timestamp()], |row| {
+            Ok(PendingUpload {
+                event: event_from_row(row)?,
+                local_id: row.get(17)?,
+                deleted: row.get(18)?,
+                gcal_synced: row.get(19)?,
+                target_calendar_id: row.get(20)?,
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(pending)
+    }
+
+    /// Clear the upload flag after a successful push and store the etag returned by the server
+    pub fn mark_uploaded(&mut self, local_id: i64, etag: &str) -> Result<(), rusqlite::Error> {
+        self.db.execute(
+            "UPDATE sync_metadata SET
+                needs_upload = FALSE,
+                gcal_synced = TRUE,
+                gcal_etag = ?2,
+                last_sync_time = ?3,
+                upload_attempts = 0,
+                upload_error = NULL,
+                upload_retry_at = NULL
+             WHERE local_id = ?1",
+            params![local_id, etag, Local::now().timestamp()])?;
+        Ok(())
+    }
+
+    /// Keep a failed upload queued, recording the error and when to try again
+    pub fn record_upload_failure(&mut self, local_id: i64, error: &str) -> Result<(), rusqlite::Error> {
+        self.db.execute(
+            "UPDATE sync_metadata SET
+                upload_attempts = upload_attempts + 1,
+                upload_error = ?2,
+                upload_retry_at = ?3 + MIN(?4 << MIN(upload_attempts, 20), ?5)
+             WHERE local_id = ?1",
+            params![local_id, error, Local::now().timestamp(), UPLOAD_BASE_BACKOFF, UPLOAD_MAX_BACKOFF])?;
+        Ok(())
+    }
+
+    /// Remove a soft-deleted event for good, once the deletion has reached the remote calendar
+    pub fn purge_event(&mut self, local_id: i64) -> Result<(), rusqlite::Error> {
+        self.db.execute("DELETE FROM events WHERE local_id = ?1 AND deleted = TRUE", params![local_id])?;
+        Ok(())
+    }
+
+    /// All cached calendars, without their events
+    pub fn calendars(&self) -> Result<Vec<GcalCalendar>, rusqlite::Error> {
+        let mut stmt = self.db.prepare(
+            "SELECT calendar_id, display_name, color, access_role, sync_enabled, etag, last_sync_time
+             FROM calendars ORDER BY display_name")?;
+        let calendars = stmt.query_map([], |row| {
+            let access: Option<String> = row.get(3)?;
+            let last_sync_time: Option<i64> = row.get(6)?;
+            Ok(GcalCalendar {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                color: row.get(2)?,
+                description: None,
+                events: Vec::new(),
+                access: access.and_then(|access| access.parse().ok()).unwrap_or(AccessRole::Reader),
+                sync_enabled: row.get(4)?,
+                etag: row.get(5)?,
+                last_sync_time: last_sync_time.and_then(|time| DateTime::from_timestamp(time, 0)),
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(calendars)
+    }
+
+    fn query_events(&self, start: NaiveDate, end: NaiveDate, calendar_ids: Option<&[&str]>) -> Result<Vec<CalendarEvent>, rusqlite::Error> {
+        let end = end.succ_opt().unwrap_or(end);
+        // Timed events are compared against local midnights, all-day events against the UTC
+        // midnights they are stored as
+        let (local_start, local_end) = (local_day_start(start).timestamp(), local_day_start(end).timestamp());
+        let (date_start, date_end) = (start.and_time(NaiveTime::MIN).and_utc().timestamp(), end.and_time(NaiveTime::MIN).and_utc().timestamp());
+        let (outer_start, outer_end) = (local_start.min(date_start), local_end.max(date_end));
+
+        let calendar_filter = match calendar_ids {
+            Some(ids) => format!(" AND e.calendar_id IN ({})", vec!["?"; ids.len()].join(", ")),
+            None => String::new(),
+        };
+        let calendar_values = calendar_ids.unwrap_or_default().iter().map(|id| id as &dyn ToSql);
+
+        // start_time < end of range comes first so the planner can walk idx_events_time_range.
+        // Series masters are left out here and expanded into their occurrences below.
+        let mut values: Vec<&dyn ToSql> = vec![&outer_end, &outer_start, &date_end, &date_start, &local_end, &local_start];
+        values.extend(calendar_values.clone());
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {EVENT_COLUMNS} FROM events e INDEXED BY idx_events_time_range
+             LEFT JOIN sync_metadata s ON s.local_id = e.local_id
+             WHERE e.start_time < ?1 AND e.end_time > ?2 AND e.deleted = FALSE AND e.recurrence IS NULL
+                AND CASE WHEN e.all_day THEN e.start_time < ?3 AND e.end_time > ?4
+                    ELSE e.start_time < ?5 AND e.end_time > ?6 END{calendar_filter}"))?;
+        let mut events = stmt.query_map(params_from_iter(values), event_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut values: Vec<&dyn ToSql> = vec![&outer_end];
+        values.extend(calendar_values);
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {EVENT_COLUMNS} FROM events e
+             LEFT JOIN sync_metadata s ON s.local_id = e.local_id
+             WHERE e.recurrence IS NOT NULL AND e.start_time < ?1 AND e.deleted = FALSE{calendar_filter}"))?;
+        let masters = stmt.query_map(params_from_iter(values), event_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        let in_range = |event: &CalendarEvent| {
+            let (start, end) = (event.start_time.timestamp(), event.end_time.timestamp());
+            if event.all_day {
+                start < date_end && end > date_start
+            } else {
+                start < local_end && end > local_start
+            }
+        };
+        for master in &masters {
+            // Modified and cancelled instances replace the occurrence they were scheduled as
+            let exceptions = self.series_exceptions(&master.event_id, &master.calendar_id)?;
+            let outer = |secs| DateTime::from_timestamp(secs, 0).unwrap_or_default();
+            events.extend(occurrences(master, outer(outer_start), outer(outer_end)).into_iter()
+                .filter(|occurrence| in_range(occurrence) && !exceptions.contains(&occurrence.start_time.timestamp())));
+        }
+
+        events.sort_by_key(|event| (event.start_time, event.end_time));
+        Ok(events)
+    }
+
+    /// Original start times of the modified or cancelled instances of a series
+    fn series_exceptions(&self, recurring_event_id: &str, calendar_id: &str) -> Result<Vec<i64>, rusqlite::Error> {
+        let mut stmt = self.db.prepare(
+            "SELECT original_start_time FROM events
+             WHERE recurring_event_id = ?1 AND calendar_id = ?2 AND original_start_time IS NOT NULL")?;
+        let exceptions = stmt.query_map(params![recurring_event_id, calendar_id], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(exceptions)
+    }
+}
+
+fn event_from_row(row: &Row) -> Result<CalendarEvent, rusqlite::Error> {
+    let timestamp = |idx: usize| -> Result<DateTime<Utc>, rusqlite::Error> {
+        let secs: i64 = row.get(idx)?;
+        DateTime::from_timestamp(secs, 0).ok_or(rusqlite::Error::IntegralValueOutOfRange(idx, secs))
+    };
+    let source_type: String = row.get(8)?;
+    Ok(CalendarEvent {
+        title: row.get(0)?,
+        description: row.get(1)?,
+        location: row.get(2)?,
+        start_time: timestamp(3)?,
+        end_time: timestamp(4)?,
+        etag: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+        event_id: row.get(6)?,
+        calendar_id: row.get(7)?,
+        source_type: source_type.parse()
+            .map_err(|_| rusqlite::Error::InvalidColumnType(8, "source_type".to_string(), rusqlite::types::Type::Text))?,
+        updated: row.get::<_, Option<bool>>(9)?.unwrap_or(false),
+        attendees: row.get::<_, Option<String>>(10)?
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default(),
+        upload_error: row.get(11)?,
+        all_day: row.get::<_, Option<bool>>(12)?.unwrap_or(false),
+        recurrence: row.get::<_, Option<String>>(13)?
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default(),
+        recurring_event_id: row.get(14)?,
+        original_start_time: row.get::<_, Option<i64>>(15)?.and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        time_zone: row.get(16)?,
+    })
+}
+
+/// Queue the deletion of the Google Calendar event mirroring an org event, as a soft-deleted
+/// event of the target calendar that the upload worker deletes remotely and then purges
+fn queue_mirror_deletion(tx: &Transaction, gcal_event_id: &str, calendar_id: &str) -> Result<(), rusqlite::Error> {
+    let local_id: i64 = tx.query_row(
+        "INSERT INTO events (event_id, calendar_id, source_type, title, start_time, end_time, deleted)
+         VALUES (?1, ?2, ?3, '', 0, 0, TRUE)
+         ON CONFLICT(event_id, calendar_id) DO UPDATE SET deleted = TRUE
+         RETURNING local_id",
+        params![gcal_event_id, calendar_id, SourceType::GoogleCalendar.as_str()],
+        |row| row.get(0))?;
+    tx.execute(
+        "INSERT INTO sync_metadata (local_id, source_type, gcal_synced, needs_upload) VALUES (?1, ?2, TRUE, TRUE)
+         ON CONFLICT(local_id) DO UPDATE SET
+            gcal_synced = TRUE,
+            needs_upload = TRUE,
+            upload_attempts = 0,
+            upload_error = NULL,
+            upload_retry_at = NULL",
+        params![local_id, SourceType::GoogleCalendar.as_str()])?;
+    Ok(())
+}
+
+/// Insert the deleted row of a cancelled occurrence, keyed on its instance ID
+fn insert_cancelled_occurrence(tx: &Transaction, event: &CalendarEvent) -> Result<i64, rusqlite::Error> {
+    tx.query_row(
+        "INSERT INTO events (event_id, calendar_id, source_type, title, start_time, end_time, all_day, recurring_event_id, original_start_time, deleted)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, TRUE)
+         ON CONFLICT(event_id, calendar_id) DO UPDATE SET deleted = TRUE
+         RETURNING local_id",
+        params![
+            &event.event_id,
+            &event.calendar_id,
+            event.source_type.as_str(),
+            &event.title,
+            event.start_time.timestamp(),
+            event.end_time.timestamp(),
+            event.all_day,
+            &event.recurring_event_id,
+            event.original_start_time.map(|time| time.timestamp()),
+        ],
+        |row| row.get(0))
+}
+
+/// The fields of a remote event that can conflict with local edits, kept in sync_metadata.remote_version
+fn remote_version_json(event: &CalendarEvent) -> String {
+    serde_json::json!({
+        "title": event.title,
+        "description": event.description,
+        "location": event.location,
+        "attendees": event.attendees,
+        "start_time": event.start_time.timestamp(),
+        "end_time": event.end_time.timestamp(),
+        "all_day": event.all_day,
+        "etag": event.etag,
+    }).to_string()
+}
+
+/// The remote version of a conflicted event: its stored fields over a copy of the local event
+fn remote_from_json(local: &CalendarEvent, remote: &serde_json::Value) -> CalendarEvent {
+    let text = |key: &str| remote[key].as_str().map(str::to_string);
+    let timestamp = |key: &str| remote[key].as_i64().and_then(|secs| DateTime::from_timestamp(secs, 0));
+    CalendarEvent {
+        title: text("title").unwrap_or_default(),
+        description: text("description"),
+        location: text("location"),
+        attendees: serde_json::from_value(remote["attendees"].clone()).unwrap_or_default(),
+        start_time: timestamp("start_time").unwrap_or(local.start_time),
+        end_time: timestamp("end_time").unwrap_or(local.end_time),
+        all_day: remote["all_day"].as_bool().unwrap_or(local.all_day),
+        etag: text("etag").unwrap_or_default(),
+        updated: false,
+        upload_error: None,
+        ..local.clone()
+    }
+}
+
+fn recurrence_text(recurrence: &[String]) -> Option<String> {
+    if recurrence.is_empty() {
+        None
+    } else {
+        Some(recurrence.join("\n"))
+    }
+}
+
+fn attendees_json(attendees: &[String]) -> Option<String> {
+    if attendees.is_empty() {
+        None
+    } else {
+        serde_json::to_string(attendees).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::event::SourceType;
+
+    fn calendar(id: &str) -> GcalCalendar {
+        GcalCalendar {
+            id: id.to_string(),
+            name: "Work".to_string(),
+            color: Some("#9fe1e7".to_string()),
+            description: None,
+            events: Vec::new(),
+            access: AccessRole::Owner,
+            sync_enabled: true,
+            etag: Some("\"1\"".to_string()),
+            last_sync_time: None,
+        }
+    }
+
+    fn event(event_id: &str, calendar_id: &str, etag: &str) -> CalendarEvent {
+        CalendarEvent {
+            title: "Standup".to_string(),
+            description: None,
+            location: Some("Room 1".to_string()),
+            attendees: vec!["Ada".to_string(), "grace@example.com".to_string()],
+            start_time: Utc.with_ymd_and_hms(2025, 6, 2, 9, 0, 0).unwrap(),
+            end_time: Utc.with_ymd_and_hms(2025, 6, 2, 9, 15, 0).unwrap(),
+            all_day: false,
+            etag: etag.to_string(),
+            event_id: event_id.to_string(),
+            calendar_id: calendar_id.to_string(),
+            source_type: SourceType::GoogleCalendar,
+            updated: false,
+            upload_error: None,
+            recurrence: Vec::new(),
+            recurring_event_id: None,
+            original_start_time: None,
+            time_zone: None,
+        }
+    }
+
+    #[test]
+    fn sync_calendar_creates_then_leaves_unchanged() {
+        let mut db = Database::new(":memory:").unwrap();
+        let cal = calendar("work@example.com");
+
+        assert_eq!(db.sync_calendar(&cal).unwrap(), SyncOutcome::Created);
+        assert_eq!(db.sync_calendar(&cal).unwrap(), SyncOutcome::Unchanged);
+
+        let (name, color, access, etag): (String, Option<String>, String, Option<String>) = db.db.query_row(
+            "SELECT display_name, color, access_role, etag FROM calendars WHERE calendar_id = ?1",
+            params![&cal.id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap();
+        assert_eq!(name, "Work");
+        assert_eq!(color.as_deref(), Some("#9fe1e7"));
+        assert_eq!(access, "owner");
+        assert_eq!(etag.as_deref(), Some("\"1\""));
+    }
+
+    #[test]
+    fn disabled_calendars_stay_disabled_and_lose_their_events() {
+        let mut db = Database::new(":memory:").unwrap();
+        let cal = calendar("work@example.com");
+        db.sync_calendar(&cal).unwrap();
+        db.sync_event(&event("abc", &cal.id, "\"1\"")).unwrap();
+        db.set_calendar_sync_token(&cal.id, Some("token")).unwrap();
+        db.save_local_event(&event("new", &cal.id, "")).unwrap();
+
+        db.set_calendar_enabled(&cal.id, false).unwrap();
+        // Refreshing the calendar list doesn't turn it back on
+        assert_eq!(db.sync_calendar(&cal).unwrap(), SyncOutcome::Unchanged);
+        assert!(!db.calendars().unwrap()[0].sync_enabled);
+        // Only the event waiting to be uploaded is left, and it waits for the calendar to be back on
+        let ids: Vec<String> = db.db.prepare("SELECT event_id FROM events").unwrap()
+            .query_map([], |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(ids, ["new"]);
+        assert!(db.pending_uploads().unwrap().is_empty());
+        assert_eq!(db.calendar_sync_token(&cal.id).unwrap(), None);
+        assert_eq!(db.calendars().unwrap()[0].last_sync_time, None);
+
+        db.set_calendar_enabled(&cal.id, true).unwrap();
+        assert!(db.calendars().unwrap()[0].sync_enabled);
+        let pending = db.pending_uploads().unwrap();
+        assert_eq!(pending.len(), 1);
+        db.mark_uploaded(pending[0].local_id, "\"1\"").unwrap();
+
+        // Calendars taken out of the config are disabled the same way
+        db.sync_event(&event("abc", &cal.id, "\"1\"")).unwrap();
+        db.disable_calendars_except(&["primary".to_string()]).unwrap();
+        assert!(!db.calendars().unwrap()[0].sync_enabled);
+        let count: i64 = db.db.query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn sync_calendar_updates_changed_fields() {
+        let mut db = Database::new(":memory:").unwrap();
+        let mut cal = calendar("work@example.com");
+        db.sync_calendar(&cal).unwrap();
+
+        cal.name = "Team".to_string();
+        cal.color = None;
+        cal.access = AccessRole::Reader;
+        assert_eq!(db.sync_calendar(&cal).unwrap(), SyncOutcome::Updated);
+
+        let (name, color, access): (String, Option<String>, String) = db.db.query_row(
+            "SELECT display_name, color, access_role FROM calendars WHERE calendar_id = ?1",
+            params![&cal.id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        assert_eq!(name, "Team");
+        assert_eq!(color, None);
+        assert_eq!(access, "reader");
+
+        let count: i64 = db.db.query_row("SELECT COUNT(*) FROM calendars", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+    }
+
+    fn local(y: i32, m: u32, d: u32, h: u32) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(h, 0, 0).unwrap()
+            .and_local_timezone(Local).unwrap().to_utc()
+    }
+
+    #[test]
+    fn events_queries_filter_by_date_calendar_and_deleted() {
+        let mut db = Database::new(":memory:").unwrap();
+        let day = NaiveDate::from_ymd_opt(2025, 6, 2).unwrap();
+
+        let mut morning = event("a", "work@example.com", "1");
+        (morning.start_time, morning.end_time) = (local(2025, 6, 2, 9), local(2025, 6, 2, 10));
+        let mut overnight = event("b", "home@example.com", "1");
+        (overnight.start_time, overnight.end_time) = (local(2025, 6, 1, 22), local(2025, 6, 2, 1));
+        let mut next_day = event("c", "work@example.com", "1");
+        (next_day.start_time, next_day.end_time) = (local(2025, 6, 3, 9), local(2025, 6, 3, 10));
+        let mut deleted = event("d", "work@example.com", "1");
+        (deleted.start_time, deleted.end_time) = (local(2025, 6, 2, 12), local(2025, 6, 2, 13));
+        for ev in [&morning, &overnight, &next_day, &deleted] {
+            db.sync_event(ev).unwrap();
+        }
+        db.db.execute("UPDATE events SET deleted = TRUE WHERE event_id = 'd'", []).unwrap();
+
+        let ids = |events: Vec<CalendarEvent>| events.into_iter().map(|e| e.event_id).collect::<Vec<_>>();
+        assert_eq!(ids(db.events_on_day(day).unwrap()), ["b", "a"]);
+        assert_eq!(ids(db.events_in_range(day, day.succ_opt().unwrap()).unwrap()), ["b", "a", "c"]);
+        assert_eq!(ids(db.events_for_calendars(&["work@example.com"], day, day.succ_opt().unwrap()).unwrap()), ["a", "c"]);
+        assert!(db.events_for_calendars(&[], day, day).unwrap().is_empty());
+    }
+
+    #[test]
+    fn local_changes_are_queued_until_uploaded() {
+        let mut db = Database::new(":memory:").unwrap();
+        let mut ev = event("abc", "work@example.com", "\"1\"");
+        db.sync_event(&ev).unwrap();
+        assert!(db.pending_uploads().unwrap().is_empty());
+
+        ev.title = "Moved standup".to_string();
+        db.save_local_event(&ev).unwrap();
+        let pending = db.pending_uploads().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].event.title, "Moved standup");
+        assert!(pending[0].gcal_synced && !pending[0].deleted);
+
+        db.mark_uploaded(pending[0].local_id, "\"3\"").unwrap();
+        assert!(db.pending_uploads().unwrap().is_empty());
+
+        db.delete_local_event(&ev).unwrap();
+        let day = ev.start_time.with_timezone(&Local).date_naive();
+        assert!(db.events_on_day(day).unwrap().is_empty());
+        let pending = db.pending_uploads().unwrap();
+        assert!(pending[0].deleted);
+
+        db.purge_event(pending[0].local_id).unwrap();
+        assert!(db.pending_uploads().unwrap().is_empty());
+        let count: i64 = db.db.query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn remote_changes_to_unpushed_edits_conflict_until_resolved() {
+        let mut db = Database::new(":memory:").unwrap();
+        let mut ev = event("abc", "work@example.com", "\"1\"");
+        db.sync_event(&ev).unwrap();
+        ev.title = "Moved standup".to_string();
+        db.save_local_event(&ev).unwrap();
+
+        // Remote changes don't overwrite an edit that hasn't been pushed yet, nor get overwritten by it
+        let mut remote = event("abc", "work@example.com", "\"2\"");
+        remote.title = "Remote title".to_string();
+        remote.location = Some("Room 2".to_string());
+        assert_eq!(db.sync_event(&remote).unwrap(), SyncOutcome::Conflict);
+        assert!(db.pending_uploads().unwrap().is_empty());
+
+        let conflicts = db.conflicts().unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].local.title, "Moved standup");
+        assert_eq!(conflicts[0].remote.title, "Remote title");
+        assert_eq!(conflicts[0].remote.location.as_deref(), Some("Room 2"));
+        assert_eq!(conflicts[0].remote.etag, "\"2\"");
+
+        // Editing it again keeps the remote version waiting to be resolved
+        let mut edited = conflicts[0].local.clone();
+        edited.title = "Moved standup again".to_string();
+        db.save_local_event(&edited).unwrap();
+        assert!(db.pending_uploads().unwrap().is_empty());
+        let conflicts = db.conflicts().unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].local.title, "Moved standup again");
+        assert_eq!(conflicts[0].remote.title, "Remote title");
+
+        // Keeping only remote fields settles it without an upload
+        db.resolve_conflict(&conflicts[0].remote, "\"2\"", false).unwrap();
+        assert!(db.conflicts().unwrap().is_empty());
+        assert!(db.pending_uploads().unwrap().is_empty());
+        assert_eq!(db.sync_event(&remote).unwrap(), SyncOutcome::Unchanged);
+
+        // Merging in a local change uploads it on top of the remote version
+        let mut ev = db.events_on_day(ev.start_time.with_timezone(&Local).date_naive()).unwrap().remove(0);
+        ev.title = "Merged".to_string();
+        db.save_local_event(&ev).unwrap();
+        assert_eq!(db.sync_event(&event("abc", "work@example.com", "\"3\"")).unwrap(), SyncOutcome::Conflict);
+        db.resolve_conflict(&ev, "\"3\"", true).unwrap();
+        let pending = db.pending_uploads().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].event.title.as_str(), pending[0].event.location.as_deref()), ("Merged", Some("Room 2")));
+    }
+
+    #[test]
+    fn failed_uploads_back_off_and_stay_visible() {
+        let mut db = Database::new(":memory:").unwrap();
+        let ev = event("abc", "work@example.com", "");
+        db.save_local_event(&ev).unwrap();
+        let local_id = db.pending_uploads().unwrap()[0].local_id;
+
+        db.record_upload_failure(local_id, "403 Forbidden").unwrap();
+        assert!(db.pending_uploads().unwrap().is_empty());
+        let day = ev.start_time.with_timezone(&Local).date_naive();
+        let stored = db.events_on_day(day).unwrap();
+        assert!(stored[0].updated);
+        assert_eq!(stored[0].upload_error.as_deref(), Some("403 Forbidden"));
+
+        // Editing again retries straight away
+        db.save_local_event(&ev).unwrap();
+        assert_eq!(db.pending_uploads().unwrap().len(), 1);
+        assert_eq!(db.events_on_day(day).unwrap()[0].upload_error, None);
+    }
+
+    #[test]
+    fn sync_tokens_are_per_calendar_and_reset_keeps_local_changes() {
+        let mut db = Database::new(":memory:").unwrap();
+        db.sync_calendar(&calendar("work@example.com")).unwrap();
+        db.sync_calendar(&calendar("home@example.com")).unwrap();
+        assert!(db.calendars().unwrap().iter().all(|calendar| calendar.last_sync_time.is_none()));
+        db.set_calendar_sync_token("work@example.com", Some("work-token")).unwrap();
+        db.set_calendar_sync_token("home@example.com", Some("home-token")).unwrap();
+        assert!(db.calendars().unwrap().iter().all(|calendar| calendar.last_sync_time.is_some()));
+        // Remote updates to the calendar itself leave the token alone
+        db.sync_calendar(&GcalCalendar { name: "Renamed".to_string(), ..calendar("work@example.com") }).unwrap();
+        assert_eq!(db.calendar_sync_token("work@example.com").unwrap().as_deref(), Some("work-token"));
+
+        db.sync_event(&event("synced", "work@example.com", "1")).unwrap();
+        db.save_local_event(&event("edited", "work@example.com", "")).unwrap();
+        db.sync_event(&event("other", "home@example.com", "1")).unwrap();
+
+        db.reset_calendar_sync("work@example.com").unwrap();
+        assert_eq!(db.calendar_sync_token("work@example.com").unwrap(), None);
+        assert_eq!(db.calendar_sync_token("home@example.com").unwrap().as_deref(), Some("home-token"));
+        let remaining: Vec<String> = db.db.prepare("SELECT event_id FROM events ORDER BY event_id").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(remaining, ["edited", "other"]);
+    }
+
+    #[test]
+    fn all_day_events_match_their_dates_in_any_time_zone() {
+        let mut db = Database::new(":memory:").unwrap();
+        let mut holiday = event("h", "work@example.com", "1");
+        holiday.all_day = true;
+        holiday.start_time = Utc.with_ymd_and_hms(2025, 6, 2, 0, 0, 0).unwrap();
+        holiday.end_time = Utc.with_ymd_and_hms(2025, 6, 4, 0, 0, 0).unwrap();
+        db.sync_event(&holiday).unwrap();
+
+        let day = |d| NaiveDate::from_ymd_opt(2025, 6, d).unwrap();
+        assert!(db.events_on_day(day(1)).unwrap().is_empty());
+        assert!(db.events_on_day(day(2)).unwrap()[0].all_day);
+        assert_eq!(db.events_on_day(day(3)).unwrap().len(), 1);
+        assert!(db.events_on_day(day(4)).unwrap().is_empty());
+        assert_eq!(holiday.all_day_dates(), Some((day(2), day(3))));
+    }
+
+    #[test]
+    fn sync_event_skips_matching_etag() {
+        let mut db = Database::new(":memory:").unwrap();
+        db.sync_calendar(&calendar("work@example.com")).unwrap();
+
+        let mut ev = event("abc", "work@example.com", "\"1\"");
+        assert_eq!(db.sync_event(&ev).unwrap(), SyncOutcome::Created);
+        assert_eq!(db.sync_event(&ev).unwrap(), SyncOutcome::Unchanged);
+
+        ev.title = "Retro".to_string();
+        ev.etag = "\"2\"".to_string();
+        assert_eq!(db.sync_event(&ev).unwrap(), SyncOutcome::Updated);
+
+        let stored = db.events_on_day(ev.start_time.with_timezone(&Local).date_naive()).unwrap();
+        assert_eq!(stored[0].attendees, ev.attendees);
+
+        let (title, etag): (String, String) = db.db.query_row(
+            "SELECT e.title, s.gcal_etag FROM events e JOIN sync_metadata s ON s.local_id = e.local_id
+             WHERE e.event_id = 'abc'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!(title, "Retro");
+        assert_eq!(etag, "\"2\"");
+    }
+
+    #[test]
+    fn recurring_series_expand_with_their_exceptions() {
+        let mut db = Database::new(":memory:").unwrap();
+        let utc = |m, d, h| Utc.with_ymd_and_hms(2025, m, d, h, 0, 0).unwrap();
+        let mut series = event("series", "work@example.com", "1");
+        (series.start_time, series.end_time) = (utc(6, 2, 8), utc(6, 2, 9));
+        series.time_zone = Some("Europe/London".to_string());
+        series.recurrence = vec![
+            "RRULE:FREQ=WEEKLY;COUNT=5".to_string(),
+            "EXDATE;TZID=Europe/London:20250609T090000".to_string(),
+        ];
+        db.sync_event(&series).unwrap();
+        let mut moved = event("series_20250616T080000Z", "work@example.com", "1");
+        (moved.start_time, moved.end_time) = (utc(6, 16, 9), utc(6, 16, 10));
+        moved.title = "Moved standup".to_string();
+        moved.recurring_event_id = Some("series".to_string());
+        moved.original_start_time = Some(utc(6, 16, 8));
+        db.sync_event(&moved).unwrap();
+        db.cancel_occurrence("series_20250623T080000Z", "work@example.com", "series", utc(6, 23, 8)).unwrap();
+
+        let june = |d| NaiveDate::from_ymd_opt(2025, 6, d).unwrap();
+        let events = db.events_in_range(june(1), june(30)).unwrap();
+        let ids: Vec<&str> = events.iter().map(|e| e.event_id.as_str()).collect();
+        assert_eq!(ids, ["series_20250602T080000Z", "series_20250616T080000Z", "series_20250630T080000Z"]);
+        assert_eq!(events[1].title, "Moved standup");
+        assert_eq!(events[2].start_time, utc(6, 30, 8));
+        assert!(events.iter().all(|e| e.recurring_event_id.as_deref() == Some("series") && !e.is_recurring()));
+
+        // Deleting an occurrence offline hides it and queues the cancellation of that instance
+        db.delete_local_event(&events[2]).unwrap();
+        assert_eq!(db.events_in_range(june(1), june(30)).unwrap().len(), 2);
+        let pending = db.pending_uploads().unwrap();
+        assert!(pending[0].deleted && pending[0].gcal_synced);
+        db.mark_uploaded(pending[0].local_id, "").unwrap();
+        assert_eq!(db.events_in_range(june(1), june(30)).unwrap().len(), 2);
+
+        // Cancelling the whole series drops its instances along with it
+        db.remove_cancelled_event("series", "work@example.com").unwrap();
+        assert!(db.events_in_range(june(1), june(30)).unwrap().is_empty());
+        let count: i64 = db.db.query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn all_day_series_recur_on_their_dates() {
+        let mut db = Database::new(":memory:").unwrap();
+        let mut birthday = event("birthday", "home@example.com", "1");
+        birthday.all_day = true;
+        (birthday.start_time, birthday.end_time) = (Utc.with_ymd_and_hms(2020, 6, 5, 0, 0, 0).unwrap(), Utc.with_ymd_and_hms(2020, 6, 6, 0, 0, 0).unwrap());
+        birthday.recurrence = vec!["RRULE:FREQ=YEARLY".to_string()];
+        db.sync_event(&birthday).unwrap();
+
+        let day = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        let events = db.events_on_day(day(2025, 6, 5)).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_id, "birthday_20250605");
+        assert_eq!(events[0].all_day_dates(), Some((day(2025, 6, 5), day(2025, 6, 5))));
+        assert!(db.events_on_day(day(2025, 6, 4)).unwrap().is_empty());
+        assert!(db.events_on_day(day(2025, 6, 6)).unwrap().is_empty());
+        assert_eq!(db.events_in_range(day(2024, 1, 1), day(2025, 12, 31)).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn org_files_replace_their_events_on_import() {
+        let mut db = Database::new(":memory:").unwrap();
+        let path = "/home/ada/agenda.org";
+        let content = "* Dentist <2025-06-02 Mon 09:00>\n* Review\nSCHEDULED: <2025-06-02 Mon 14:00>\n";
+        let hash = crate::org::content_hash(content);
+        assert_eq!(db.org_file_hash(path).unwrap(), None);
+        db.sync_org_file(path, &hash, &crate::org::parse_org(content, path)).unwrap();
+        assert_eq!(db.org_file_hash(path).unwrap(), Some(hash));
+
+        let day = NaiveDate::from_ymd_opt(2025, 6, 2).unwrap();
+        let titles = |db: &Database| db.events_on_day(day).unwrap().into_iter().map(|e| e.title).collect::<Vec<_>>();
+        assert_eq!(titles(&db), ["Dentist", "Review"]);
+        assert!(db.events_on_day(day).unwrap().iter().all(|e| e.source_type == SourceType::OrgMode && e.calendar_id == path));
+        let (start, end): (i64, i64) = db.db.query_row(
+            "SELECT org_line_start, org_line_end FROM sync_metadata WHERE org_file_path = ?1 ORDER BY org_line_start DESC",
+            params![path],
+            |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!((start, end), (2, 3));
+
+        let content = "* Dentist <2025-06-02 Mon 10:00>\n";
+        db.sync_org_file(path, &crate::org::content_hash(content), &crate::org::parse_org(content, path)).unwrap();
+        assert_eq!(titles(&db), ["Dentist"]);
+        let count: i64 = db.db.query_row("SELECT COUNT(*) FROM sync_metadata", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+
+        // A file without timestamps is remembered too, so that it isn't imported again
+        let content = "* Someday\n";
+        db.sync_org_file(path, &crate::org::content_hash(content), &crate::org::parse_org(content, path)).unwrap();
+        assert!(titles(&db).is_empty());
+        assert_eq!(db.org_file_hash(path).unwrap(), Some(crate::org::content_hash(content)));
+    }
+
+    #[test]
+    fn org_events_are_mirrored_one_way() {
+        let mut db = Database::new(":memory:").unwrap();
+        db.sync_calendar(&calendar("work@example.com")).unwrap();
+        let path = "/home/ada/agenda.org";
+        let import = |db: &mut Database, content: &str| {
+            let mut events = crate::org::parse_org(content, path);
+            for org in &mut events {
+                org.target_calendar_id = Some("work@example.com".to_string());
+            }
+            db.sync_org_file(path, &crate::org::content_hash(content), &events).unwrap();
+        };
+
+        import(&mut db, "* Dentist <2025-06-02 Mon 09:00>\n");
+        let pending = db.pending_uploads().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].target_calendar_id.as_deref(), Some("work@example.com"));
+        assert!(!pending[0].gcal_synced);
+        db.mark_uploaded(pending[0].local_id, "\"1\"").unwrap();
+
+        // The mirror coming back from Google Calendar doesn't show up twice
+        let mirror_id = crate::org::mirror_event_id("work@example.com", &pending[0].event.event_id);
+        let mut mirror = event(&mirror_id, "work@example.com", "\"1\"");
+        mirror.title = "Dentist".to_string();
+        assert_eq!(db.sync_event(&mirror).unwrap(), SyncOutcome::Unchanged);
+
+        // Moving lines around doesn't upload again, changing the event does
+        import(&mut db, "\n* Dentist <2025-06-02 Mon 09:00>\n");
+        assert!(db.pending_uploads().unwrap().is_empty());
+        import(&mut db, "* Dentist <2025-06-02 Mon 10:00>\n");
+        let pending = db.pending_uploads().unwrap();
+        assert!(pending[0].gcal_synced && !pending[0].deleted);
+        db.mark_uploaded(pending[0].local_id, "\"2\"").unwrap();
+
+        // Nothing is pushed to a calendar turned off in the calendar list until it is back on
+        db.set_calendar_enabled("work@example.com", false).unwrap();
+        import(&mut db, "* Dentist <2025-06-02 Mon 11:00>\n");
+        assert!(db.pending_uploads().unwrap().is_empty());
+        db.set_calendar_enabled("work@example.com", true).unwrap();
+        let pending = db.pending_uploads().unwrap();
+        assert_eq!(pending.len(), 1);
+        db.mark_uploaded(pending[0].local_id, "\"3\"").unwrap();
+
+        // Removing the entry deletes its mirror
+        import(&mut db, "* Nothing planned\n");
+        let pending = db.pending_uploads().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].event.event_id, mirror_id);
+        assert_eq!(pending[0].event.calendar_id, "work@example.com");
+        assert!(pending[0].deleted && pending[0].gcal_synced);
     }
 }
@@ -0,0 +1,96 @@
+use std::{fmt, fs::OpenOptions, io::{self, Write}, path::PathBuf, sync::OnceLock};
+
+use chrono::Local;
+
+/// Everything that can go wrong in ultima. Errors about a single remote item are logged and the
+/// item is skipped, see `log`; the others are shown in the TUI's status bar.
+#[derive(Debug)]
+pub enum UltimaError {
+    Api(Box<google_calendar3::Error>), // Request to Google Calendar failed, boxed as it is large
+    Auth(String), // Client secret unreadable or OAuth flow failed
+    Offline(String), // Google couldn't be reached while signing in
+    Database(rusqlite::Error),
+    Conversion(String), // Remote item that can't be turned into a calendar or event
+    Org(String), // Reading, importing or writing back an org file failed
+    Config(String),
+    Terminal(io::Error), // Drawing the TUI or reading key presses failed
+}
+
+impl UltimaError {
+    /// Whether the request never reached Google, e.g. without a network connection
+    pub fn is_offline(&self) -> bool {
+        match self {
+            UltimaError::Offline(_) => true,
+            UltimaError::Api(error) => matches!(error.as_ref(), google_calendar3::Error::HttpError(_)),
+            _ => false,
+        }
+    }
+
+    /// HTTP status of a failed API request, whether or not the server sent a JSON error body
+    pub fn status_code(&self) -> Option<u16> {
+        let UltimaError::Api(error) = self else {
+            return None;
+        };
+        match error.as_ref() {
+            google_calendar3::Error::Failure(response) => Some(response.status().as_u16()),
+            google_calendar3::Error::BadRequest(body) => body["error"]["code"].as_u64().map(|code| code as u16),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for UltimaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UltimaError::Api(error) => write!(f, "Google Calendar: {error}"),
+            UltimaError::Auth(error) => write!(f, "Authentication: {error}"),
+            UltimaError::Offline(error) => write!(f, "Offline: {error}"),
+            UltimaError::Database(error) => write!(f, "Database: {error}"),
+            UltimaError::Conversion(error) => write!(f, "Skipped {error}"),
+            UltimaError::Org(error) => write!(f, "Org: {error}"),
+            UltimaError::Config(error) => write!(f, "Config: {error}"),
+            UltimaError::Terminal(error) => write!(f, "Terminal: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for UltimaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UltimaError::Api(error) => Some(error.as_ref()),
+            UltimaError::Database(error) => Some(error),
+            UltimaError::Terminal(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<google_calendar3::Error> for UltimaError {
+    fn from(error: google_calendar3::Error) -> Self {
+        UltimaError::Api(Box::new(error))
+    }
+}
+
+impl From<rusqlite::Error> for UltimaError {
+    fn from(error: rusqlite::Error) -> Self {
+        UltimaError::Database(error)
+    }
+}
+
+static LOG_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Append errors passed to `log` to the file at `path`. Until this is called they are dropped.
+pub fn init_log(path: PathBuf) {
+    let _ = LOG_FILE.set(path);
+}
+
+/// Record an error that doesn't stop anything, such as a remote event that was skipped. The TUI
+/// owns the terminal, so these go to the log file rather than stderr.
+pub fn log(error: &UltimaError) {
+    let Some(path) = LOG_FILE.get() else {
+        return;
+    };
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = writeln!(file, "{} {error}", Local::now().format("%Y-%m-%d %H:%M:%S"));
+    }
+}
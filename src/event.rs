@@ -1,5 +1,43 @@
-use chrono::{DateTime, Local, Utc};
-use google_calendar3::api::{CalendarListEntry, Event};
+use std::{str::FromStr, sync::atomic::{AtomicU32, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+use chrono::{DateTime, Days, Local, NaiveDate, NaiveTime, TimeDelta, Utc};
+use google_calendar3::api::{CalendarListEntry, Event, EventDateTime};
+
+use crate::error::UltimaError;
+
+/// Local midnight at the start of `day`. When a DST change skips midnight, the day starts at the
+/// first local time that exists, such as 01:00.
+pub fn local_day_start(day: NaiveDate) -> DateTime<Local> {
+    let midnight = day.and_hms_opt(0, 0, 0).unwrap();
+    (0..24 * 60)
+        .find_map(|minute| (midnight + TimeDelta::minutes(minute)).and_local_timezone(Local).earliest())
+        .unwrap_or_else(|| midnight.and_utc().with_timezone(&Local)) // The whole day was skipped
+}
+
+/// Convert a Google Calendar start or end, returning whether it is date-only. Dates are stored as
+/// UTC midnight so that they stay on the same day in every time zone.
+pub fn event_time(time: EventDateTime) -> Option<(DateTime<Utc>, bool)> {
+    match (time.date_time, time.date) {
+        (Some(date_time), _) => Some((date_time, false)),
+        (None, Some(date)) => Some((date.and_time(NaiveTime::MIN).and_utc(), true)),
+        (None, None) => None,
+    }
+}
+
+/// Generate an ID for an event created locally. Google Calendar accepts client-chosen IDs made of
+/// base32hex characters (a-v, 0-9), so the event keeps the same ID once uploaded.
+pub fn generate_event_id() -> String {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    const BASE32HEX: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let value = (nanos << 16) | (COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff) as u128;
+    let mut id = String::from("ultima");
+    for shift in (0..=125).rev().step_by(5) {
+        id.push(BASE32HEX[((value >> shift) & 0x1f) as usize] as char);
+    }
+    id
+}
 
 //TODO make sure to add assertions that the events match calendar id
 #[derive(Debug, Clone)]
@@ -12,26 +50,23 @@ pub struct GcalCalendar {
     pub access: AccessRole,
     pub sync_enabled: bool,
     pub etag: Option<String>,
-    pub last_sync_time: DateTime<Utc>,
+    pub last_sync_time: Option<DateTime<Utc>>, // When its events were last synced, None if never
 }
 
 impl GcalCalendar {
-    pub fn from_calendar_list_entry(entry: CalendarListEntry) -> Result<Self, ()> {
-        let id = entry.id.expect("Calendar ID not provided");
-        let name = entry.summary.expect("Calendar name not provided");
+    /// Fails when the entry has no ID or no valid access role. A calendar without a name is
+    /// named after its ID.
+    pub fn from_calendar_list_entry(entry: CalendarListEntry) -> Result<Self, UltimaError> {
+        let id = entry.id.ok_or_else(|| UltimaError::Conversion("calendar without an ID".to_string()))?;
+        let name = entry.summary.unwrap_or_else(|| id.clone());
         let color = entry.background_color;
         let description = entry.description;
         let events: Vec<CalendarEvent> = Vec::new();
-        let access = match entry.access_role.expect("Calendar Access Role not provided").as_str() {
-            "freeBusyReader" => {Some(AccessRole::FreeBusyReader)},
-            "reader" => {Some(AccessRole::Reader)},
-            "writer" => {Some(AccessRole::Writer)},
-            "owner" => {Some(AccessRole::Owner)},
-            _ => {None}
-        }.expect("Invalid Access Role");
+        let access = entry.access_role.as_deref().unwrap_or_default().parse()
+            .map_err(|error| UltimaError::Conversion(format!("calendar {id}: {error}")))?;
         let sync_enabled = true;
         let etag = entry.etag;
-        let last_sync_time = Local::now().to_utc(); // TODO Still yet to sync, how to resolve?
+        let last_sync_time = None; // Set once its events are synced, see Database::set_calendar_sync_token
         Ok(Self {
             id,
             name,
@@ -54,9 +89,63 @@ pub enum AccessRole {
     FreeBusyReader,
 }
 
+impl AccessRole {
+    /// Whether events in a calendar with this role can be created, edited or deleted
+    pub fn can_write(&self) -> bool {
+        matches!(self, AccessRole::Owner | AccessRole::Writer)
+    }
+
+    /// Value used by the Google Calendar API and stored in calendars.access_role
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessRole::Owner => "owner",
+            AccessRole::Writer => "writer",
+            AccessRole::Reader => "reader",
+            AccessRole::FreeBusyReader => "freeBusyReader",
+        }
+    }
+}
+
+impl FromStr for AccessRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(AccessRole::Owner),
+            "writer" => Ok(AccessRole::Writer),
+            "reader" => Ok(AccessRole::Reader),
+            "freeBusyReader" => Ok(AccessRole::FreeBusyReader),
+            _ => Err(format!("invalid access role \"{s}\"")),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum SourceType {
     GoogleCalendar,
+    OrgMode,
+}
+
+impl SourceType {
+    /// Value stored in the `source_type` columns, see sql/schema.sql
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceType::GoogleCalendar => "gcal",
+            SourceType::OrgMode => "orgmode",
+        }
+    }
+}
+
+impl FromStr for SourceType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gcal" => Ok(SourceType::GoogleCalendar),
+            "orgmode" => Ok(SourceType::OrgMode),
+            _ => Err(format!("invalid source type \"{s}\"")),
+        }
+    }
 }
 
 /// CalendarEvent is designed for events rendering via the TUI
@@ -69,43 +158,151 @@ pub struct CalendarEvent {
     pub title: String,
     pub description: Option<String>,
     pub location: Option<String>,
+    pub attendees: Vec<String>,
     pub start_time: DateTime<Utc>,
     pub end_time: DateTime<Utc>,
+    pub all_day: bool, // Date-only event, start_time and end_time are UTC midnights and end_time is exclusive
     pub etag: String,
     pub event_id: String,
     pub calendar_id: String,
     pub source_type: SourceType,
     pub updated: bool, // Updated locally since last sync, needs to be uploaded to gcal
+    pub upload_error: Option<String>, // Why the last attempt to upload the local change failed
+    pub recurrence: Vec<String>, // RRULE, EXDATE and RDATE lines of a recurring series' master event
+    pub recurring_event_id: Option<String>, // Series this occurrence belongs to
+    pub original_start_time: Option<DateTime<Utc>>, // Start of the occurrence before it was modified
+    pub time_zone: Option<String>, // IANA time zone the series recurs in
 }
 
 impl CalendarEvent {
-    pub fn from_gcal_api(event: Event, calendar_id: String) -> Result<Self, ()> {
-        let title = event.summary.expect("No title provided");
+    /// Fails when the event has no ID, etag, start or end. Events without a title, which Google
+    /// allows, get the placeholder its web UI shows.
+    pub fn from_gcal_api(event: Event, calendar_id: String) -> Result<Self, UltimaError> {
+        let event_id = event.id.ok_or_else(|| UltimaError::Conversion(format!("event without an ID in {calendar_id}")))?;
+        let missing = |field: &str| UltimaError::Conversion(format!("event {event_id} in {calendar_id}: no {field}"));
+        let title = event.summary.unwrap_or_else(|| "(No title)".to_string());
         let description = event.description;
         let location = event.location;
-        let start_time = event.start.expect("No start time provided").date_time.expect("Unable to convert given start time");
-        let end_time = event.end.expect("No end time provided").date_time.expect("Unable to convert given end time");
-        let etag = event.etag.expect("No etag provided");
-        let event_id = event.id.expect("No id provided");
+        let attendees = event.attendees.unwrap_or_default().into_iter()
+            .filter_map(|attendee| attendee.display_name.or(attendee.email))
+            .collect();
+        let start = event.start.ok_or_else(|| missing("start"))?;
+        let time_zone = start.time_zone.clone();
+        let (start_time, start_all_day) = event_time(start).ok_or_else(|| missing("start time"))?;
+        let (end_time, end_all_day) = event.end.and_then(event_time).ok_or_else(|| missing("end time"))?;
+        let all_day = start_all_day && end_all_day;
+        let etag = event.etag.ok_or_else(|| missing("etag"))?;
         let source_type = SourceType::GoogleCalendar;
         let updated = false;
+        let upload_error = None;
+        let recurrence = event.recurrence.unwrap_or_default();
+        let recurring_event_id = event.recurring_event_id;
+        let original_start_time = event.original_start_time.and_then(event_time).map(|(time, _)| time);
 
         Ok(Self {
             title,
             description,
             location,
+            attendees,
             start_time,
             end_time,
+            all_day,
             etag,
             event_id,
             calendar_id,
             source_type,
             updated,
+            upload_error,
+            recurrence,
+            recurring_event_id,
+            original_start_time,
+            time_zone,
         })
     }
 
-    pub fn update(event: Event) {
-        todo!()
+    /// New event created locally, not yet uploaded
+    pub fn new_local(title: String, start_time: DateTime<Utc>, end_time: DateTime<Utc>, calendar_id: String) -> Self {
+        Self {
+            title,
+            description: None,
+            location: None,
+            attendees: Vec::new(),
+            start_time,
+            end_time,
+            all_day: false,
+            etag: String::new(),
+            event_id: generate_event_id(),
+            calendar_id,
+            source_type: SourceType::GoogleCalendar,
+            updated: true,
+            upload_error: None,
+            recurrence: Vec::new(),
+            recurring_event_id: None,
+            original_start_time: None,
+            time_zone: None,
+        }
+    }
+
+    /// Request body for inserting this event through the Google Calendar API, see `to_gcal_patch`.
+    /// Attendees are left out so that patches never touch the remote guest list. The status
+    /// restores an event that was deleted remotely under the same ID.
+    pub fn to_gcal_api(&self) -> Event {
+        Event {
+            id: Some(self.event_id.clone()),
+            status: Some("confirmed".to_string()),
+            summary: Some(self.title.clone()),
+            description: self.description.clone(),
+            location: self.location.clone(),
+            start: Some(self.gcal_time(self.start_time)),
+            end: Some(self.gcal_time(self.end_time)),
+            recurrence: Some(self.recurrence.clone()).filter(|recurrence| !recurrence.is_empty()),
+            ..Default::default()
+        }
+    }
+
+    /// Request body for patching this event. A patch keeps the remote value of fields it doesn't get, so
+    /// cleared fields are sent empty to clear them remotely too. Occurrences of a series carry no
+    /// recurrence of their own.
+    pub fn to_gcal_patch(&self) -> Event {
+        Event {
+            description: Some(self.description.clone().unwrap_or_default()),
+            location: Some(self.location.clone().unwrap_or_default()),
+            recurrence: self.recurring_event_id.is_none().then(|| self.recurrence.clone()),
+            ..self.to_gcal_api()
+        }
+    }
+
+    fn gcal_time(&self, time: DateTime<Utc>) -> EventDateTime {
+        if self.all_day {
+            EventDateTime { date: Some(time.date_naive()), ..Default::default() }
+        } else {
+            EventDateTime { date_time: Some(time), time_zone: self.time_zone.clone(), ..Default::default() }
+        }
+    }
+
+    /// Start and end of the event in local time. All-day events run from local midnight on their
+    /// first date to local midnight after their last date, wherever the user is.
+    pub fn local_span(&self) -> (DateTime<Local>, DateTime<Local>) {
+        match self.all_day_dates() {
+            Some((first, last)) => (local_day_start(first), local_day_start(last + Days::new(1))),
+            None => (self.start_time.with_timezone(&Local), self.end_time.with_timezone(&Local)),
+        }
+    }
+
+    /// Master event of a recurring series, stored once and expanded into its occurrences when
+    /// queried, see `recurrence::occurrences`
+    pub fn is_recurring(&self) -> bool {
+        !self.recurrence.is_empty()
+    }
+
+    /// First and last date, both inclusive, of an all-day event
+    pub fn all_day_dates(&self) -> Option<(NaiveDate, NaiveDate)> {
+        if !self.all_day {
+            return None;
+        }
+        let first = self.start_time.date_naive();
+        let last = self.end_time.date_naive().pred_opt().unwrap_or(first).max(first);
+        Some((first, last))
     }
 
     //TODO make it so that updated is set to false after syncing and set to true after changing
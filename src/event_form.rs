@@ -0,0 +1,265 @@
+use chrono::{DateTime, Days, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Color, Stylize},
+    symbols::border,
+    text::{Line, Span},
+    widgets::{Block, Paragraph, Widget, Wrap},
+};
+
+use crate::event::{CalendarEvent, GcalCalendar, SourceType};
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+const DATE_FORMAT: &str = "%Y-%m-%d"; // Start and end both given as dates make an all-day event
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Title,
+    Start,
+    End,
+    Location,
+    Description,
+    Calendar,
+}
+
+const FIELDS: [Field; 6] = [Field::Title, Field::Start, Field::End, Field::Location, Field::Description, Field::Calendar];
+
+/// What the TUI should do after the form handled a key press
+pub enum FormAction {
+    None,
+    Cancel,
+    Save(Box<CalendarEvent>),
+}
+
+/// Popup form for creating a new event or editing an existing one
+pub struct EventForm {
+    original: Option<CalendarEvent>,
+    title: String,
+    start: String,
+    end: String,
+    location: String,
+    description: String,
+    calendars: Vec<GcalCalendar>, // Calendars the event may be saved to, all writable
+    calendar_index: usize,
+    focus: usize,
+    error: Option<String>,
+}
+
+impl EventForm {
+    /// Form for a new one hour event at 09:00 on `date`. Returns None if none of the calendars
+    /// can be written to.
+    pub fn new_event(date: NaiveDate, calendars: Vec<GcalCalendar>) -> Option<Self> {
+        let calendars: Vec<GcalCalendar> = calendars.into_iter().filter(|calendar| calendar.access.can_write()).collect();
+        if calendars.is_empty() {
+            return None;
+        }
+
+        let start = date.and_hms_opt(9, 0, 0).unwrap();
+        Some(Self {
+            original: None,
+            title: String::new(),
+            start: start.format(TIME_FORMAT).to_string(),
+            end: (start + TimeDelta::hours(1)).format(TIME_FORMAT).to_string(),
+            location: String::new(),
+            description: String::new(),
+            calendars,
+            calendar_index: 0,
+            focus: 0,
+            error: None,
+        })
+    }
+
+    /// Form prefilled with an existing event. Returns None if its calendar is read-only.
+    /// Moving an event to another calendar isn't supported, so the calendar is fixed.
+    pub fn edit_event(event: CalendarEvent, calendar: GcalCalendar) -> Option<Self> {
+        if !calendar.access.can_write() {
+            return None;
+        }
+
+        let (start, end) = match event.all_day_dates() {
+            Some((first, last)) => (first.format(DATE_FORMAT).to_string(), last.format(DATE_FORMAT).to_string()),
+            None => (
+                event.start_time.with_timezone(&Local).format(TIME_FORMAT).to_string(),
+                event.end_time.with_timezone(&Local).format(TIME_FORMAT).to_string(),
+            ),
+        };
+        Some(Self {
+            title: event.title.clone(),
+            start,
+            end,
+            location: event.location.clone().unwrap_or_default(),
+            description: event.description.clone().unwrap_or_default(),
+            original: Some(event),
+            calendars: vec![calendar],
+            calendar_index: 0,
+            focus: 0,
+            error: None,
+        })
+    }
+
+    pub fn handle_key_event(&mut self, key_event: KeyEvent) -> FormAction {
+        match key_event.code {
+            KeyCode::Esc => return FormAction::Cancel,
+            KeyCode::Enter => match self.build_event() {
+                Ok(event) => return FormAction::Save(Box::new(event)),
+                Err(error) => self.error = Some(error),
+            },
+            KeyCode::Tab | KeyCode::Down => self.focus = (self.focus + 1) % FIELDS.len(),
+            KeyCode::BackTab | KeyCode::Up => self.focus = (self.focus + FIELDS.len() - 1) % FIELDS.len(),
+            KeyCode::Left if FIELDS[self.focus] == Field::Calendar => {
+                self.calendar_index = (self.calendar_index + self.calendars.len() - 1) % self.calendars.len();
+            }
+            KeyCode::Right if FIELDS[self.focus] == Field::Calendar => {
+                self.calendar_index = (self.calendar_index + 1) % self.calendars.len();
+            }
+            KeyCode::Backspace => {
+                if let Some(text) = self.text_mut(FIELDS[self.focus]) {
+                    text.pop();
+                }
+            }
+            KeyCode::Char(c) => {
+                if let Some(text) = self.text_mut(FIELDS[self.focus]) {
+                    text.push(c);
+                }
+            }
+            _ => {}
+        }
+        FormAction::None
+    }
+
+    fn text(&self, field: Field) -> Option<&String> {
+        match field {
+            Field::Title => Some(&self.title),
+            Field::Start => Some(&self.start),
+            Field::End => Some(&self.end),
+            Field::Location => Some(&self.location),
+            Field::Description => Some(&self.description),
+            Field::Calendar => None,
+        }
+    }
+
+    /// Org files only get the title and times written back, so their other fields can't be edited
+    fn read_only(&self, field: Field) -> bool {
+        let org = self.original.as_ref().is_some_and(|event| event.source_type == SourceType::OrgMode);
+        org && matches!(field, Field::Location | Field::Description)
+    }
+
+    fn text_mut(&mut self, field: Field) -> Option<&mut String> {
+        if self.read_only(field) {
+            return None;
+        }
+        match field {
+            Field::Title => Some(&mut self.title),
+            Field::Start => Some(&mut self.start),
+            Field::End => Some(&mut self.end),
+            Field::Location => Some(&mut self.location),
+            Field::Description => Some(&mut self.description),
+            Field::Calendar => None,
+        }
+    }
+
+    /// Validate the fields and build the event to save
+    fn build_event(&self) -> Result<CalendarEvent, String> {
+        let title = self.title.trim();
+        if title.is_empty() {
+            return Err("Title is required".to_string());
+        }
+        let dates = NaiveDate::parse_from_str(self.start.trim(), DATE_FORMAT).ok()
+            .zip(NaiveDate::parse_from_str(self.end.trim(), DATE_FORMAT).ok());
+        let (start_time, end_time, all_day) = match dates {
+            Some((first, last)) => {
+                if last < first {
+                    return Err("End date must not be before start date".to_string());
+                }
+                // The end date is inclusive in the form but exclusive when stored
+                (first.and_time(NaiveTime::MIN).and_utc(), (last + Days::new(1)).and_time(NaiveTime::MIN).and_utc(), true)
+            }
+            None => {
+                let example = Local::now().format(TIME_FORMAT);
+                let start_time = parse_local_time(&self.start).ok_or(format!("Start must look like {example}, or a date for all day"))?;
+                let end_time = parse_local_time(&self.end).ok_or(format!("End must look like {example}, or a date for all day"))?;
+                if end_time <= start_time {
+                    return Err("End must be after start".to_string());
+                }
+                (start_time, end_time, false)
+            }
+        };
+        let calendar_id = self.calendars[self.calendar_index].id.clone();
+
+        let mut event = match &self.original {
+            Some(original) => original.clone(),
+            None => CalendarEvent::new_local(String::new(), start_time, end_time, calendar_id),
+        };
+        event.title = title.to_string();
+        event.start_time = start_time;
+        event.end_time = end_time;
+        event.all_day = all_day;
+        event.location = Some(self.location.trim().to_string()).filter(|location| !location.is_empty());
+        event.description = Some(self.description.trim().to_string()).filter(|description| !description.is_empty());
+        event.updated = true;
+        Ok(event)
+    }
+}
+
+fn parse_local_time(text: &str) -> Option<DateTime<Utc>> {
+    let naive = NaiveDateTime::parse_from_str(text.trim(), TIME_FORMAT).ok()?;
+    Some(naive.and_local_timezone(Local).earliest()?.to_utc())
+}
+
+impl Widget for &EventForm {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let title = if self.original.is_some() { " [edit event] " } else { " [new event] " };
+        let instructions = Line::from(vec![
+            " Next field ".into(),
+            "<Tab>".blue().bold(),
+            " Calendar ".into(),
+            "<←/→>".blue().bold(),
+            " Save ".into(),
+            "<Enter>".blue().bold(),
+            " Cancel ".into(),
+            "<Esc>".blue().bold(),
+            " ".into(),
+        ]);
+        let form_block = Block::bordered()
+            .title(Line::from(title.bold()).centered())
+            .title_bottom(instructions.centered())
+            .border_set(border::THICK);
+
+        let mut lines: Vec<Line> = FIELDS.iter().enumerate().map(|(i, field)| {
+            let label = match field {
+                Field::Title => "Title",
+                Field::Start => "Start",
+                Field::End => "End",
+                Field::Location => "Location",
+                Field::Description => "Description",
+                Field::Calendar => "Calendar",
+            };
+            let focused = i == self.focus;
+            let value: Span = match self.text(*field) {
+                Some(text) if self.read_only(*field) => format!("{text} (read-only in org files)").dark_gray(),
+                Some(text) if focused => format!("{text}█").into(),
+                Some(text) => text.clone().into(),
+                None if self.calendars.len() > 1 => format!("◀ {} ▶", self.calendars[self.calendar_index].name).into(),
+                None => self.calendars[self.calendar_index].name.clone().into(),
+            };
+            let label: Span = format!(" {label:<12}").bold();
+            if focused {
+                Line::from(vec![label, value]).bg(Color::Indexed(17))
+            } else {
+                Line::from(vec![label, value])
+            }
+        }).collect();
+
+        if let Some(error) = &self.error {
+            lines.push(Line::default());
+            lines.push(Line::from(format!(" {error}").red()));
+        }
+
+        Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(form_block)
+            .render(area, buf);
+    }
+}
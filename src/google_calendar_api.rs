@@ -1,119 +1,309 @@
-use std::error::Error;
+use std::path::Path;
+use chrono::{DateTime, Utc};
 use google_calendar3::{
-    hyper_rustls::{self, HttpsConnector}, hyper_util::{self, client::legacy::connect::HttpConnector}, yup_oauth2, CalendarHub
+    hyper_rustls::{self, HttpsConnector}, hyper_util::{self, client::legacy::connect::HttpConnector}, CalendarHub
 };
-use dirs::home_dir;
 
-use crate::event::{CalendarEvent, GcalCalendar};
+use crate::{
+    auth::{self, AuthFlow},
+    error::{log, UltimaError},
+    event::{event_time, CalendarEvent, GcalCalendar},
+};
 
 pub struct GoogleCalendarAPI {
     hub: CalendarHub<HttpsConnector<HttpConnector>>,
-    sync_token: Option<String>,
+}
+
+/// Query options for `GoogleCalendarAPI::get_events`
+#[derive(Debug, Clone, Default)]
+pub struct EventListOptions {
+    pub time_min: Option<DateTime<Utc>>,
+    pub time_max: Option<DateTime<Utc>>,
+    pub single_events: bool, // Expand recurring events into their instances
+}
+
+/// An event deleted remotely. Cancelled occurrences of a recurring series also name their series
+/// and the time the occurrence was scheduled at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CancelledEvent {
+    pub event_id: String,
+    pub recurring_event_id: Option<String>,
+    pub original_start_time: Option<DateTime<Utc>>,
+}
+
+/// Result of listing a calendar's events
+pub struct EventSync {
+    pub events: Vec<CalendarEvent>,
+    pub cancelled: Vec<CancelledEvent>, // Deleted events, reported during incremental sync and for occurrences of a series
+    pub next_sync_token: Option<String>, // Pass to the next get_events of the same calendar
 }
 
 impl GoogleCalendarAPI {
-    pub async fn new() -> Result<Self, Box<dyn Error>> {
-        let mut secret_path = home_dir().ok_or("Unable to determine home directory")?;
-        secret_path.push(".ultima/secret.json");
-        let secret = yup_oauth2::read_application_secret(secret_path)
-            .await?;
-        let auth_builder = yup_oauth2::InstalledFlowAuthenticator::builder(secret, yup_oauth2::InstalledFlowReturnMethod::HTTPRedirect);
-        let mut token_path = home_dir().ok_or("Unable to determine home directory")?;
-        token_path.push(".ultima/token.json");
-        let auth = auth_builder.persist_tokens_to_disk(&token_path).build().await?;
-        let scopes = &[
-            "https://www.googleapis.com/auth/calendar",
-            "https://www.googleapis.com/auth/calendar.events",
-            "https://www.googleapis.com/auth/calendar.readonly",
-            "https://www.googleapis.com/auth/calendar.events.readonly",
-        ];
-
-        auth.token(scopes).await?;
-
-        let client = hyper_util::client::legacy::Client::builder(
-            hyper_util::rt::TokioExecutor::new()
-        )
-            .build(
-                hyper_rustls::HttpsConnectorBuilder::new()
-                    .with_native_roots()
-                    .unwrap()
-                    .https_or_http()
-                    .enable_http1()
-                    .build()
-            );
-
-        let hub = CalendarHub::new(client, auth);
-        let sync_token = None;
+    /// Authenticate with the client secret at `secret_path` using `flow`, caching the token at
+    /// `token_path`. Without a flow only a cached token is used, see `auth::authenticate`.
+    pub async fn new(secret_path: &Path, token_path: &Path, flow: Option<AuthFlow>) -> Result<Self, UltimaError> {
+        let auth = auth::authenticate(secret_path, token_path, flow).await?;
+        let hub = CalendarHub::new(Self::http_client()?, auth);
         Ok(Self {
             hub,
-            sync_token,
         })
     }
 
-    pub async fn get_calendars(&mut self) -> Result<Vec<GcalCalendar>, ()> {
-        let result = if let Some(token) = &self.sync_token {
-            self.hub.calendar_list().list().sync_token(token.as_str()).doit().await
+    /// Unauthenticated client talking to a mock server
+    #[cfg(test)]
+    fn with_base_url(base_url: String) -> Self {
+        // Installed by main outside of tests; fails harmlessly if another test got there first
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let mut hub = CalendarHub::new(Self::http_client().unwrap(), google_calendar3::common::NoToken);
+        hub.base_url(base_url);
+        Self {
+            hub,
         }
-        else {
-            self.hub.calendar_list().list().doit().await
-        };
+    }
+
+    pub fn http_client() -> Result<google_calendar3::common::Client<HttpsConnector<HttpConnector>>, UltimaError> {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .map_err(|error| UltimaError::from(google_calendar3::Error::Io(error)))?
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(hyper_util::client::legacy::Client::builder(
+            hyper_util::rt::TokioExecutor::new()
+        )
+            .build(connector))
+    }
 
+    /// Every calendar in the user's calendar list. The list is small, so it is always fetched in
+    /// full rather than incrementally. Entries that can't be read are logged and skipped.
+    pub async fn get_calendars(&self) -> Result<Vec<GcalCalendar>, UltimaError> {
         let mut calendars = Vec::new();
-        let (_, mut calendar_list) = result.expect("Invalid calendar list");
-        let mut page_token = calendar_list.next_page_token;
-        for entry in calendar_list.items.expect("Invalid calendar list items") {
-            calendars.push(GcalCalendar::from_calendar_list_entry(entry).expect("Unable to convert calendar into GcalCalendar struct"));
-        }
+        let mut page_token: Option<String> = None;
 
-        while let Some(page_token_str) = page_token { 
-            let result = if let Some(token) = &self.sync_token {
-                self.hub.calendar_list().list().sync_token(token.as_str()).page_token(&page_token_str).doit().await
+        loop {
+            let mut request = self.hub.calendar_list().list();
+            if let Some(page_token) = &page_token {
+                request = request.page_token(page_token);
+            }
+
+            let (_, calendar_list) = request.doit().await?;
+            for entry in calendar_list.items.unwrap_or_default() {
+                match GcalCalendar::from_calendar_list_entry(entry) {
+                    Ok(calendar) => calendars.push(calendar),
+                    Err(error) => log(&error),
+                }
             }
-            else {
-                self.hub.calendar_list().list().page_token(&page_token_str).doit().await
-            };
 
-            (_, calendar_list) = result.expect("Invalid calendar list");
             page_token = calendar_list.next_page_token;
-            for entry in calendar_list.items.expect("Invalid calendar list items") {
-                calendars.push(GcalCalendar::from_calendar_list_entry(entry).expect("Unable to convert calendar into GcalCalendar struct"));
+            if page_token.is_none() {
+                return Ok(calendars);
             }
         }
-        self.sync_token = calendar_list.next_sync_token;
-        Ok(calendars)
     }
 
-    pub async fn get_events(&mut self, calendar: GcalCalendar) -> Result<Vec<CalendarEvent>, ()> {
-        let calendar_id = calendar.id;
-        let result = if let Some(token) = &self.sync_token {
-            self.hub.events().list(&calendar_id.clone()).sync_token(token.as_str()).doit().await
+    /// Create the event in its calendar, returning the new etag. If an event with the same ID
+    /// already exists it is patched instead.
+    pub async fn insert_event(&self, event: &CalendarEvent) -> Result<String, UltimaError> {
+        match self.hub.events().insert(event.to_gcal_api(), &event.calendar_id).doit().await.map_err(UltimaError::from) {
+            Ok((_, created)) => created.etag.ok_or_else(|| UltimaError::Conversion(format!("etag of inserted event {}", event.event_id))),
+            // The ID is taken when an earlier insert went through but its response was lost
+            Err(error) if error.status_code() == Some(409) => self.patch_event(event).await,
+            Err(error) => Err(error),
         }
-        else {
-            self.hub.events().list(&calendar_id.clone()).doit().await
-        };
-        let mut events = Vec::new();
-        let (_, mut event_list) = result.expect("Invalid event list");
-        let mut page_token = event_list.next_page_token;
-        for entry in event_list.items.expect("Invalid event list items") {
-            events.push(CalendarEvent::from_gcal_api(entry, calendar_id.clone()).expect("Unable to convert event into CalendarEvent"));
+    }
+
+    /// Overwrite the event's title, description, location and times, returning the new etag
+    pub async fn patch_event(&self, event: &CalendarEvent) -> Result<String, UltimaError> {
+        let (_, patched) = self.hub.events().patch(event.to_gcal_patch(), &event.calendar_id, &event.event_id).doit().await?;
+        patched.etag.ok_or_else(|| UltimaError::Conversion(format!("etag of patched event {}", event.event_id)))
+    }
+
+    /// Delete the event, treating an event that is already gone as deleted
+    pub async fn delete_event(&self, calendar_id: &str, event_id: &str) -> Result<(), UltimaError> {
+        match self.hub.events().delete(calendar_id, event_id).doit().await.map_err(UltimaError::from) {
+            Ok(_) => Ok(()),
+            Err(error) if matches!(error.status_code(), Some(404 | 410)) => Ok(()),
+            Err(error) => Err(error),
         }
+    }
 
-        while let Some(page_token_str) = page_token {
-            let result = if let Some(token) = &self.sync_token {
-                self.hub.events().list(&calendar_id.clone()).sync_token(token.as_str()).page_token(&page_token_str).doit().await
+    /// Events of one calendar, following every page. With a sync token from a previous call only
+    /// the changes since then are returned, including cancelled events; an expired token fails
+    /// with HTTP 410, see `is_sync_token_expired`. The time bounds in `options` are only sent
+    /// without a sync token, since the API rejects the combination. Events that can't be read are
+    /// logged and skipped.
+    pub async fn get_events(&self, calendar_id: &str, sync_token: Option<&str>, options: &EventListOptions) -> Result<EventSync, UltimaError> {
+        let mut sync = EventSync {
+            events: Vec::new(),
+            cancelled: Vec::new(),
+            next_sync_token: None,
+        };
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut request = self.hub.events().list(calendar_id).single_events(options.single_events);
+            if let Some(token) = sync_token {
+                request = request.sync_token(token);
+            } else {
+                if let Some(time_min) = options.time_min {
+                    request = request.time_min(time_min);
+                }
+                if let Some(time_max) = options.time_max {
+                    request = request.time_max(time_max);
+                }
+            }
+            if let Some(page_token) = &page_token {
+                request = request.page_token(page_token);
+            }
+
+            let (_, event_list) = request.doit().await?;
+            for entry in event_list.items.unwrap_or_default() {
+                // Cancelled events only carry their id, and their series for cancelled occurrences
+                if entry.status.as_deref() == Some("cancelled") {
+                    sync.cancelled.extend(entry.id.map(|event_id| CancelledEvent {
+                        event_id,
+                        recurring_event_id: entry.recurring_event_id,
+                        original_start_time: entry.original_start_time.and_then(event_time).map(|(time, _)| time),
+                    }));
+                    continue;
+                }
+                match CalendarEvent::from_gcal_api(entry, calendar_id.to_string()) {
+                    Ok(event) => sync.events.push(event),
+                    Err(error) => log(&error),
+                }
             }
-            else {
-                self.hub.events().list(&calendar_id.clone()).page_token(&page_token_str).doit().await
-            };
 
-            let mut events = Vec::new();
-            (_, event_list) = result.expect("Invalid event list");
             page_token = event_list.next_page_token;
-            for entry in event_list.items.expect("Invalid event list items") {
-                events.push(CalendarEvent::from_gcal_api(entry, calendar_id.clone()).expect("Unable to convert event into CalendarEvent"));
+            if page_token.is_none() {
+                // Only the last page carries the token for the next incremental sync
+                sync.next_sync_token = event_list.next_sync_token;
+                return Ok(sync);
             }
         }
-        self.sync_token = event_list.next_sync_token;
-        Ok(events)
+    }
+}
+
+/// Whether listing events failed because the sync token expired, in which case the calendar has
+/// to be synced again from scratch
+pub fn is_sync_token_expired(error: &UltimaError) -> bool {
+    error.status_code() == Some(410)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use chrono::TimeZone;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+
+    /// Serve canned JSON responses on a local port, recording the request line of every call
+    async fn mock_server(respond: fn(&str) -> (u16, String)) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let request_line = request.lines().next().unwrap_or_default().to_string();
+                let (status, body) = respond(&request_line);
+                recorded.lock().unwrap().push(request_line);
+                let response = format!(
+                    "HTTP/1.1 {status} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len());
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (base_url, requests)
+    }
+
+    fn event_json(id: &str) -> String {
+        format!(r#"{{"id": "{id}", "etag": "\"1\"", "status": "confirmed", "summary": "Event {id}",
+            "start": {{"dateTime": "2025-06-02T09:00:00Z"}}, "end": {{"dateTime": "2025-06-02T10:00:00Z"}}}}"#)
+    }
+
+    const CANCELLED_OCCURRENCE: &str = r#"{"id": "series_20250609T090000Z", "status": "cancelled", "recurringEventId": "series",
+        "originalStartTime": {"dateTime": "2025-06-09T09:00:00Z"}}"#;
+
+    #[tokio::test]
+    async fn get_events_follows_every_page() {
+        let (base_url, requests) = mock_server(|request| {
+            let body = if request.contains("pageToken=page3") {
+                format!(r#"{{"items": [{}], "nextSyncToken": "sync-1"}}"#, event_json("e"))
+            } else if request.contains("pageToken=page2") {
+                format!(r#"{{"items": [{}, {{"id": "gone", "status": "cancelled"}}, {}], "nextPageToken": "page3"}}"#, event_json("c"), CANCELLED_OCCURRENCE)
+            } else {
+                format!(r#"{{"items": [{}, {}], "nextPageToken": "page2"}}"#, event_json("a"), event_json("b"))
+            };
+            (200, body)
+        }).await;
+        let api = GoogleCalendarAPI::with_base_url(base_url);
+        let options = EventListOptions {
+            time_min: Some(Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()),
+            time_max: None,
+            single_events: true,
+        };
+
+        let sync = api.get_events("work@example.com", None, &options).await.unwrap();
+
+        let ids: Vec<&str> = sync.events.iter().map(|event| event.event_id.as_str()).collect();
+        assert_eq!(ids, ["a", "b", "c", "e"]);
+        assert!(sync.events.iter().all(|event| event.calendar_id == "work@example.com"));
+        let cancelled: Vec<&str> = sync.cancelled.iter().map(|cancelled| cancelled.event_id.as_str()).collect();
+        assert_eq!(cancelled, ["gone", "series_20250609T090000Z"]);
+        assert_eq!(sync.cancelled[0].recurring_event_id, None);
+        assert_eq!(sync.cancelled[1].recurring_event_id.as_deref(), Some("series"));
+        assert_eq!(sync.cancelled[1].original_start_time, Some(Utc.with_ymd_and_hms(2025, 6, 9, 9, 0, 0).unwrap()));
+        assert_eq!(sync.next_sync_token.as_deref(), Some("sync-1"));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|request| request.contains("singleEvents=true") && request.contains("timeMin=")));
+    }
+
+    #[tokio::test]
+    async fn get_events_skips_events_it_cannot_read() {
+        let (base_url, _) = mock_server(|_| {
+            let untitled = r#"{"id": "untitled", "etag": "\"1\"", "start": {"date": "2025-06-02"}, "end": {"date": "2025-06-03"}}"#;
+            let no_start = r#"{"id": "no-start", "etag": "\"1\"", "summary": "Broken", "end": {"date": "2025-06-03"}}"#;
+            (200, format!(r#"{{"items": [{untitled}, {no_start}, {}]}}"#, event_json("a")))
+        }).await;
+        let api = GoogleCalendarAPI::with_base_url(base_url);
+
+        let sync = api.get_events("work@example.com", None, &EventListOptions::default()).await.unwrap();
+
+        let titles: Vec<&str> = sync.events.iter().map(|event| event.title.as_str()).collect();
+        assert_eq!(titles, ["(No title)", "Event a"]);
+    }
+
+    #[tokio::test]
+    async fn get_events_with_sync_token_skips_time_bounds_and_reports_expiry() {
+        let (base_url, requests) = mock_server(|_| {
+            (410, r#"{"error": {"code": 410, "message": "Sync token is no longer valid"}}"#.to_string())
+        }).await;
+        let api = GoogleCalendarAPI::with_base_url(base_url);
+        let options = EventListOptions {
+            time_min: Some(Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()),
+            ..Default::default()
+        };
+
+        let error = api.get_events("work@example.com", Some("old-token"), &options).await.err().unwrap();
+
+        assert!(is_sync_token_expired(&error));
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].contains("syncToken=old-token"));
+        assert!(!requests[0].contains("timeMin="));
     }
 }
@@ -1,24 +1,148 @@
 mod application_state;
+mod auth;
+mod calendar_list;
+mod config;
+mod conflict_view;
+mod error;
 mod google_calendar_api;
 mod event;
+mod event_form;
+mod month_view;
+mod org;
+mod recurrence;
 mod database;
 mod tui;
+mod week_view;
+
+use std::{fs, path::Path, process::ExitCode, sync::{Arc, Mutex}};
 
-use std::io::Error;
 use chrono::Local;
-use google_calendar_api::GoogleCalendarAPI;
+
+use application_state::ApplicationState;
+use auth::AuthFlow;
+use database::Database;
+use error::UltimaError;
+use org::expand_home;
+use tui::CalendarTextUserInterface;
+
+const USAGE: &str = "usage: ultima [--auth-flow browser|device|manual]
+       ultima auth [status|login|revoke] [--auth-flow browser|device|manual]";
+
+/// What ultima was asked to do on the command line
+enum Command {
+    Run,
+    AuthStatus,
+    AuthLogin, // Drop the cached token and sign in again
+    AuthRevoke,
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
-    rustls::crypto::aws_lc_rs::default_provider().install_default().unwrap();
-    // TUI:
-    let gcal = GoogleCalendarAPI::new().await.unwrap();
-
-    Ok(())
-    // let mut terminal = ratatui::init();
-    // let calendar_result = tui::CalendarTextUserInterface::new(Local::now().date_naive()).run(&mut terminal);
-    // ratatui::restore();
-    // calendar_result
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("ultima: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> Result<(), UltimaError> {
+    // Only fails if a provider was installed already
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let (command, auth_flow) = parse_args(std::env::args().skip(1))?;
+    let mut config = config::load()?;
+    config.auth_flow = auth_flow.unwrap_or(config.auth_flow);
+
+    match command {
+        Command::Run => {}
+        Command::AuthStatus => {
+            auth::status(&config.token_path())?.iter().for_each(|line| println!("{line}"));
+            return Ok(());
+        }
+        Command::AuthLogin => {
+            auth::login(&config.secret_path(), &config.token_path(), config.auth_flow).await?;
+            println!("Signed in, token saved to {}", config.token_path().display());
+            return Ok(());
+        }
+        Command::AuthRevoke => {
+            if auth::revoke(&config.token_path()).await? {
+                println!("Token revoked and removed");
+            } else {
+                println!("Not signed in, nothing to revoke");
+            }
+            return Ok(());
+        }
+    }
+
+    let db_path = config.database_path();
+    create_parent_dir(&db_path)?;
+    let log_path = expand_home("~/.ultima/ultima.log");
+    create_parent_dir(&log_path)?;
+    error::init_log(log_path);
+
+    let db = Arc::new(Mutex::new(Database::new(&db_path)?));
+    let first_run = db.lock().unwrap().calendars()?.is_empty();
+    let mut state = ApplicationState::new(db, config.secret_path(), config.token_path());
+    let org_result = state.import_org_files(&config.org_files(), &config.org.mirrors);
+    // Later runs start from the cache right away and connect in the background, only the first
+    // one has nothing to show before signing in
+    let first_run_result = if first_run {
+        // Let the user pick the calendars to sync before any events are imported
+        match state.sign_in(config.auth_flow).await {
+            Ok(()) => state.refresh_calendars(config.calendars.as_deref()).await,
+            error => error,
+        }
+    } else {
+        state.request_sync();
+        Ok(())
+    };
+    state.start_sync_worker(config.calendars.clone(), config.sync_interval);
+    state.start_upload_worker();
+
+    let mut tui = CalendarTextUserInterface::new(Local::now().date_naive(), state.handle(), &config);
+    if first_run {
+        tui.show_calendar_list();
+    }
+    if let Err(error) = org_result.and(first_run_result) {
+        tui.report_error(error);
+    }
+
+    let mut terminal = ratatui::init();
+    // The TUI blocks its thread between key presses, the background tasks run on the others
+    let result = tokio::task::block_in_place(|| tui.run(&mut terminal));
+    ratatui::restore();
+    result.map_err(UltimaError::Terminal)
 }
 
+fn create_parent_dir(path: &Path) -> Result<(), UltimaError> {
+    match path.parent() {
+        Some(dir) => fs::create_dir_all(dir)
+            .map_err(|error| UltimaError::Config(format!("Unable to create {}: {error}", dir.display()))),
+        None => Ok(()),
+    }
+}
 
+fn parse_args(args: impl Iterator<Item = String>) -> Result<(Command, Option<AuthFlow>), UltimaError> {
+    let mut command = Command::Run;
+    let mut auth_flow = None;
+    let mut args = args.peekable();
+    if args.next_if_eq("auth").is_some() {
+        command = match args.next_if(|arg| !arg.starts_with('-')).as_deref() {
+            None | Some("status") => Command::AuthStatus,
+            Some("login") => Command::AuthLogin,
+            Some("revoke") => Command::AuthRevoke,
+            Some(other) => return Err(UltimaError::Config(format!("unknown auth command \"{other}\"\n{USAGE}"))),
+        };
+    }
+    while let Some(arg) = args.next() {
+        let flow = match arg.split_once('=') {
+            Some(("--auth-flow", flow)) => Some(flow.to_string()),
+            None if arg == "--auth-flow" => args.next(),
+            _ => return Err(UltimaError::Config(format!("unexpected argument \"{arg}\"\n{USAGE}"))),
+        };
+        let flow = flow.ok_or_else(|| UltimaError::Config(format!("--auth-flow needs a value\n{USAGE}")))?;
+        auth_flow = Some(flow.parse().map_err(|error| UltimaError::Config(format!("{error}\n{USAGE}")))?);
+    }
+    Ok((command, auth_flow))
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use chrono::{Datelike, Days, Local, NaiveDate, Weekday};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph, Widget},
+};
+
+use crate::{
+    config::Theme,
+    event::{local_day_start, CalendarEvent},
+    week_view::first_day_of_week,
+};
+
+/// The selected date's month as six weeks of seven days, each day listing the titles that fit
+pub struct MonthView<'a> {
+    start: NaiveDate, // First day of the grid, in the week before the month starts at the latest
+    selected_date: NaiveDate,
+    today: NaiveDate,
+    events: &'a [CalendarEvent], // Events overlapping the grid
+    colors: &'a HashMap<String, Color>,
+    theme: Theme,
+}
+
+/// First and last day of the 6×7 grid showing the month `date` is in
+pub fn month_grid(date: NaiveDate, week_start: Weekday) -> (NaiveDate, NaiveDate) {
+    let start = first_day_of_week(date.with_day(1).unwrap(), week_start);
+    (start, start + Days::new(41))
+}
+
+/// How many of `total` events fit on `lines` lines, keeping the last one for "+k more" when they
+/// don't all fit
+fn visible_events(total: usize, lines: usize) -> usize {
+    if total <= lines { total } else { lines.saturating_sub(1) }
+}
+
+impl<'a> MonthView<'a> {
+    pub fn new(selected_date: NaiveDate, week_start: Weekday, events: &'a [CalendarEvent], colors: &'a HashMap<String, Color>, theme: Theme) -> Self {
+        let (start, _) = month_grid(selected_date, week_start);
+        let today = Local::now().date_naive();
+        Self { start, selected_date, today, events, colors, theme }
+    }
+
+    /// Events on `day`, all-day ones first
+    fn events_on(&self, day: NaiveDate) -> Vec<&CalendarEvent> {
+        let day_start = local_day_start(day);
+        let day_end = local_day_start(day + Days::new(1));
+        let mut events: Vec<&CalendarEvent> = self.events.iter()
+            .filter(|event| {
+                let (start, end) = event.local_span();
+                start < day_end && (end > day_start || start >= day_start)
+            })
+            .collect();
+        events.sort_by_key(|event| (!event.all_day, event.start_time));
+        events
+    }
+
+    fn event_line(&self, event: &CalendarEvent) -> Line<'static> {
+        let color = self.colors.get(&event.calendar_id).copied().unwrap_or(Color::Reset);
+        if event.all_day {
+            return Line::from(format!(" {}", event.title)).bg(color).fg(Color::Black);
+        }
+        Line::from(vec![
+            "▌".fg(color),
+            format!("{} ", event.start_time.with_timezone(&Local).format("%H:%M")).dark_gray(),
+            Span::from(event.title.clone()),
+        ])
+    }
+}
+
+impl Widget for &MonthView<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let [header_area, grid_area] = Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(area);
+        let columns = Layout::horizontal([Constraint::Fill(1); 7]);
+        let headers: [Rect; 7] = columns.areas(header_area);
+        for (header, day) in headers.iter().zip(self.start.iter_days()) {
+            Line::from(day.format("%a").to_string()).centered().bold().render(*header, buf);
+        }
+
+        let weeks: [Rect; 6] = Layout::vertical([Constraint::Fill(1); 6]).areas(grid_area);
+        let mut days = self.start.iter_days();
+        for week in weeks {
+            let cells: [Rect; 7] = columns.areas(week);
+            for (cell, day) in cells.into_iter().zip(days.by_ref()) {
+                let block = Block::new().borders(Borders::TOP | Borders::LEFT).border_style(Style::new().dark_gray());
+                let inner = block.inner(cell);
+                block.render(cell, buf);
+
+                let mut label = Line::from(format!("{} ", day.day())).right_aligned();
+                if day == self.selected_date {
+                    label = label.bg(self.theme.highlight).fg(self.theme.selected).bold();
+                } else if day == self.today {
+                    label = label.fg(self.theme.today).bold();
+                } else if day.month() != self.selected_date.month() {
+                    label = label.dark_gray();
+                }
+
+                let events = self.events_on(day);
+                let shown = visible_events(events.len(), inner.height.saturating_sub(1).into());
+                let mut lines = vec![label];
+                lines.extend(events[..shown].iter().map(|event| self.event_line(event)));
+                if shown < events.len() {
+                    lines.push(format!(" +{} more", events.len() - shown).italic().dark_gray().into());
+                }
+                let paragraph = Paragraph::new(lines);
+                if day == self.selected_date { paragraph.bg(self.theme.highlight) } else { paragraph }.render(inner, buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_covers_the_month_and_overflow_keeps_a_line() {
+        let date = |day| NaiveDate::from_ymd_opt(2025, 6, day).unwrap();
+        // June 2025 starts on a Sunday
+        assert_eq!(month_grid(date(18), Weekday::Mon), (NaiveDate::from_ymd_opt(2025, 5, 26).unwrap(), NaiveDate::from_ymd_opt(2025, 7, 6).unwrap()));
+        assert_eq!(month_grid(date(18), Weekday::Sun), (date(1), NaiveDate::from_ymd_opt(2025, 7, 12).unwrap()));
+
+        assert_eq!(visible_events(2, 3), 2);
+        assert_eq!(visible_events(3, 3), 3);
+        assert_eq!(visible_events(5, 3), 2); // Two titles and "+3 more"
+        assert_eq!(visible_events(5, 0), 0);
+    }
+}
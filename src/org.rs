@@ -0,0 +1,623 @@
+use std::{collections::HashMap, error::Error, fs, ops::Range, path::{Path, PathBuf}, sync::Mutex};
+
+use chrono::{DateTime, Days, Local, NaiveDate, NaiveTime, TimeDelta, Utc};
+use dirs::home_dir;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    database::Database,
+    event::{AccessRole, CalendarEvent, GcalCalendar, SourceType},
+};
+
+/// Length given to timestamps that only have a start time
+const DEFAULT_DURATION: TimeDelta = TimeDelta::hours(1);
+
+const TODO_KEYWORDS: [&str; 2] = ["TODO", "DONE"];
+
+/// An event read from an .org file, with the lines of the headline's section it came from
+#[derive(Debug, Clone)]
+pub struct OrgEvent {
+    pub event: CalendarEvent,
+    pub tags: Vec<String>, // Including the tags inherited from parent headlines
+    pub line_start: usize, // Line of the headline, counted from 1
+    pub line_end: usize, // Last line of the section, inclusive
+    pub target_calendar_id: Option<String>, // Google Calendar the event is mirrored to
+}
+
+/// Google Calendars that org events are mirrored to, by org file or by tag. A rule for one of an
+/// entry's tags wins over the rule for its file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrgMirrors {
+    pub files: HashMap<String, String>, // Org file path, ~ allowed, to calendar ID
+    pub tags: HashMap<String, String>, // Tag to calendar ID
+}
+
+impl OrgMirrors {
+    /// Calendar the events of the entry with `tags` in the file at canonical `path` go to
+    pub fn target(&self, path: &Path, tags: &[String]) -> Option<&str> {
+        tags.iter()
+            .find_map(|tag| self.tags.get(tag))
+            .or_else(|| self.files.iter()
+                .find(|(file, _)| fs::canonicalize(expand_home(file)).is_ok_and(|file| file == path))
+                .map(|(_, calendar_id)| calendar_id))
+            .map(String::as_str)
+    }
+}
+
+/// Replace a leading ~/ with the home directory
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// ID of the Google Calendar event mirroring an org event. It is derived from both IDs so that a
+/// mirror is never created twice, even when the database is rebuilt. Hex digits are valid
+/// base32hex, as required for client-chosen IDs.
+pub fn mirror_event_id(target_calendar_id: &str, event_id: &str) -> String {
+    format!("org{}", &content_hash(&format!("{target_calendar_id}\n{event_id}"))[..32])
+}
+
+/// Copy of an org event to insert or patch in its target calendar
+pub fn mirror_event(event: &CalendarEvent, target_calendar_id: &str) -> CalendarEvent {
+    CalendarEvent {
+        event_id: mirror_event_id(target_calendar_id, &event.event_id),
+        calendar_id: target_calendar_id.to_string(),
+        source_type: SourceType::GoogleCalendar,
+        ..event.clone()
+    }
+}
+
+/// Where an imported org event came from, as recorded in sync_metadata
+#[derive(Debug, Clone)]
+pub struct OrgLocation {
+    pub path: String,
+    pub line_start: usize,
+    pub line_end: usize,
+    pub content_hash: String, // Hash of the whole file when it was imported
+}
+
+/// Active timestamp such as <2025-06-02 Mon>, <2025-06-02 Mon 09:00-10:00> or a <...>--<...> range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrgTimestamp {
+    pub date: NaiveDate,
+    pub start: Option<NaiveTime>,
+    pub end: Option<NaiveTime>,
+    pub end_date: Option<NaiveDate>, // Last date of a range
+}
+
+impl OrgTimestamp {
+    /// Start, end and whether the timestamp is date-only, as stored for a CalendarEvent
+    fn times(&self) -> Option<(DateTime<Utc>, DateTime<Utc>, bool)> {
+        let end_date = self.end_date.unwrap_or(self.date);
+        let Some(start) = self.start else {
+            let midnight = |date: NaiveDate| date.and_time(NaiveTime::MIN).and_utc();
+            return Some((midnight(self.date), midnight(end_date + Days::new(1)), true));
+        };
+        let start_time = local_time(self.date, start)?;
+        let end_time = match self.end {
+            Some(end) => local_time(end_date, end)?,
+            None if self.end_date.is_some() => local_time(end_date, start)?,
+            None => start_time + DEFAULT_DURATION,
+        };
+        Some((start_time, end_time.max(start_time), false))
+    }
+}
+
+fn local_time(date: NaiveDate, time: NaiveTime) -> Option<DateTime<Utc>> {
+    Some(date.and_time(time).and_local_timezone(Local).earliest()?.to_utc())
+}
+
+/// Hex SHA-256 of a file's content, stored in sync_metadata.org_content_hash
+pub fn content_hash(content: &str) -> String {
+    Sha256::digest(content.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Calendar shown for the events of an org file, named after the file
+pub fn org_calendar(path: &str) -> GcalCalendar {
+    let name = Path::new(path).file_name().map_or(path.into(), |name| name.to_string_lossy());
+    GcalCalendar {
+        id: path.to_string(),
+        name: name.into_owned(),
+        color: None,
+        description: None,
+        events: Vec::new(),
+        access: AccessRole::Owner,
+        sync_enabled: true,
+        etag: None,
+        last_sync_time: None,
+    }
+}
+
+/// Import one .org file, returning whether it changed since the last import. Its canonical path
+/// is the calendar ID of its events. A file is also imported again when its events get another
+/// mirror calendar, e.g. because the mapping changed or the calendar was synced for the first time.
+pub fn import_org_file(db: &Mutex<Database>, path: &Path, mirrors: &OrgMirrors) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let canonical = fs::canonicalize(path)?;
+    let content = fs::read_to_string(&canonical)?;
+    let path = canonical.to_string_lossy();
+    let hash = content_hash(&content);
+
+    let mut events = parse_org(&content, &path);
+    let (stored_hash, stored_targets, calendars) = {
+        let db = db.lock().unwrap();
+        (db.org_file_hash(&path)?, db.org_targets(&path)?, db.calendars()?)
+    };
+    for org in &mut events {
+        // Only calendars that were synced can be written to
+        org.target_calendar_id = mirrors.target(&canonical, &org.tags)
+            .filter(|target| calendars.iter().any(|calendar| calendar.id == *target && calendar.access.can_write()))
+            .map(str::to_string);
+    }
+    let retargeted = events.iter().any(|org| stored_targets.get(&org.event.event_id) != Some(&org.target_calendar_id));
+    if stored_hash.as_deref() == Some(hash.as_str()) && !retargeted {
+        return Ok(false);
+    }
+    db.lock().unwrap().sync_org_file(&path, &hash, &events)?;
+    Ok(true)
+}
+
+/// Write an edited org event back into the file it came from. The headline gets the event's title
+/// and the event's timestamp its new times; every other byte of the file is kept. If the file was
+/// changed since it was imported nothing is written, and the file is imported again instead.
+/// Location and description aren't written back, so the event form doesn't let them be edited.
+pub fn save_org_event(db: &Mutex<Database>, event: &CalendarEvent, mirrors: &OrgMirrors) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let location = db.lock().unwrap().org_location(&event.event_id, &event.calendar_id)?
+        .ok_or("Event isn't in any imported org file")?;
+    let path = Path::new(&location.path);
+    let content = fs::read_to_string(path)?;
+    if content_hash(&content) != location.content_hash {
+        import_org_file(db, path, mirrors)?;
+        return Err(format!("{} was changed outside ultima and has been reloaded", location.path).into());
+    }
+
+    let updated = rewrite_entry(&content, &location, event)?;
+    // Write next to the file and rename over it, so the file is never left half written
+    let temp_path = format!("{}.ultima~", location.path);
+    fs::write(&temp_path, updated)?;
+    fs::set_permissions(&temp_path, fs::metadata(path)?.permissions())?;
+    fs::rename(&temp_path, path)?;
+    import_org_file(db, path, mirrors)?;
+    Ok(())
+}
+
+/// Replace the headline title and the event's timestamp within the entry at `location`
+fn rewrite_entry(content: &str, location: &OrgLocation, event: &CalendarEvent) -> Result<String, String> {
+    let mut lines: Vec<String> = content.split_inclusive('\n').map(str::to_string).collect();
+    let (start, end) = (location.line_start.saturating_sub(1), location.line_end);
+    if start >= end || end > lines.len() || headline_level(&lines[start]).is_none() {
+        return Err(format!("Line {} of {} isn't a headline anymore", location.line_start, location.path));
+    }
+    lines[start] = rewrite_headline(&lines[start], &event.title);
+
+    let section_lines: Vec<&str> = lines[start + 1..end].iter().map(|line| line.trim_end_matches(['\r', '\n'])).collect();
+    let section = Section::parse(&section_lines);
+    let not_found = || format!("The event's timestamp wasn't found at line {} of {}", location.line_start, location.path);
+    let (line, span) = match event.event_id.rsplit(':').next() {
+        Some(kind @ ("scheduled" | "deadline")) => {
+            let keyword = if kind == "scheduled" { "SCHEDULED:" } else { "DEADLINE:" };
+            let planning = lines.get(start + 1).filter(|_| end > start + 1).ok_or_else(not_found)?;
+            let after = planning.find(keyword).ok_or_else(not_found)? + keyword.len();
+            let (span, _) = timestamp_spans(planning).into_iter()
+                .find(|(span, _)| span.start >= after && planning[after..span.start].trim().is_empty())
+                .ok_or_else(not_found)?;
+            (start + 1, span)
+        }
+        Some(index) => {
+            let index: usize = index.parse().map_err(|_| not_found())?;
+            let candidates = std::iter::once(start).chain(section.timestamp_lines.iter().map(|i| start + 1 + i));
+            candidates
+                .flat_map(|line| timestamp_spans(&lines[line]).into_iter().map(move |(span, _)| (line, span)))
+                .nth(index)
+                .ok_or_else(not_found)?
+        }
+        None => return Err(not_found()),
+    };
+    let timestamp = format_timestamp(event, &lines[line][span.clone()]);
+    lines[line].replace_range(span, &timestamp);
+    Ok(lines.concat())
+}
+
+/// Headline with its title replaced, keeping the stars, TODO keyword, priority, timestamps and tags
+fn rewrite_headline(line: &str, title: &str) -> String {
+    let content = line.trim_end_matches(['\r', '\n']);
+    let skip_spaces = |pos: usize| content.len() - content[pos..].trim_start().len();
+    let mut start = skip_spaces(headline_level(content).unwrap_or(0));
+    if let Some((keyword, _)) = content[start..].split_once(' ') && TODO_KEYWORDS.contains(&keyword) {
+        start = skip_spaces(start + keyword.len());
+    }
+    if content[start..].starts_with("[#") && content.get(start + 3..start + 4) == Some("]") {
+        start = skip_spaces(start + 4);
+    }
+    let mut end = content.trim_end().len().max(start);
+    if let Some((rest, last)) = content[start..end].rsplit_once(char::is_whitespace)
+        && last.len() > 1 && last.starts_with(':') && last.ends_with(':') {
+        end = start + rest.trim_end().len();
+    }
+
+    let middle = &content[start..end];
+    let old_title = active_timestamps(middle).1;
+    if old_title.split_whitespace().eq(title.split_whitespace()) {
+        return line.to_string();
+    }
+    let mut new_middle = title.trim().to_string();
+    for (span, _) in timestamp_spans(middle) {
+        new_middle.push(' ');
+        new_middle.push_str(&middle[span]);
+    }
+    format!("{}{new_middle}{}", &content[..start], &line[end..])
+}
+
+/// Org timestamp for the event's times. Repeaters and warning delays of the timestamp it replaces
+/// are kept.
+fn format_timestamp(event: &CalendarEvent, original: &str) -> String {
+    let (original_start, _) = original.trim_start_matches('<').split_once('>').unwrap_or_default();
+    let extras: String = original_start.split_whitespace().skip(1)
+        .filter(|part| part.starts_with(['+', '.', '-']))
+        .map(|part| format!(" {part}"))
+        .collect();
+    let had_end = timestamp_spans(original).first().is_some_and(|(_, timestamp)| timestamp.end.is_some() || timestamp.end_date.is_some());
+    let stamp = |date: NaiveDate, time: Option<String>, extras: &str| {
+        let time = time.map(|time| format!(" {time}")).unwrap_or_default();
+        format!("<{}{time}{extras}>", date.format("%Y-%m-%d %a"))
+    };
+
+    if let Some((first, last)) = event.all_day_dates() {
+        return match first == last {
+            true => stamp(first, None, &extras),
+            false => format!("{}--{}", stamp(first, None, &extras), stamp(last, None, "")),
+        };
+    }
+    let (start, end) = (event.start_time.with_timezone(&Local), event.end_time.with_timezone(&Local));
+    if start.date_naive() != end.date_naive() {
+        let time = |time: DateTime<Local>| Some(time.format("%H:%M").to_string());
+        return format!("{}--{}", stamp(start.date_naive(), time(start), &extras), stamp(end.date_naive(), time(end), ""));
+    }
+    let time = if !had_end && end - start == DEFAULT_DURATION {
+        start.format("%H:%M").to_string()
+    } else {
+        format!("{}-{}", start.format("%H:%M"), end.format("%H:%M"))
+    };
+    stamp(start.date_naive(), Some(time), &extras)
+}
+
+/// Parse every headline of an org file that has an active timestamp, SCHEDULED or DEADLINE.
+/// Each timestamp becomes its own event in the calendar `calendar_id`. Event IDs come from the
+/// headline's :ID: property when it has one, otherwise from its outline path, so that they stay
+/// the same when lines are added elsewhere in the file. Renaming a headline without an :ID:
+/// gives its events new IDs.
+pub fn parse_org(content: &str, calendar_id: &str) -> Vec<OrgEvent> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut events = Vec::new();
+    let mut outline: Vec<(String, Vec<String>)> = Vec::new(); // Title and tags of each parent headline
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    let headlines: Vec<usize> = (0..lines.len()).filter(|&i| headline_level(lines[i]).is_some()).collect();
+    for (n, &start) in headlines.iter().enumerate() {
+        let end = headlines.get(n + 1).map_or(lines.len(), |&next| next); // Exclusive
+        let level = headline_level(lines[start]).unwrap();
+        let headline = Headline::parse(&lines[start][level..]);
+        outline.truncate(level - 1);
+        outline.resize(level - 1, Default::default());
+        outline.push((headline.title.clone(), headline.tags.clone()));
+        let mut tags: Vec<String> = Vec::new();
+        for tag in outline.iter().flat_map(|(_, tags)| tags) {
+            if !tags.contains(tag) {
+                tags.push(tag.clone());
+            }
+        }
+
+        let section = Section::parse(&lines[start + 1..end]);
+        let base_id = match &section.id {
+            Some(id) => id.clone(),
+            None => {
+                let path = outline.iter().map(|(title, _)| title.as_str()).collect::<Vec<_>>().join("/");
+                let count = seen.entry(path.clone()).or_default();
+                *count += 1;
+                let hash = content_hash(&format!("{path}#{count}"));
+                hash[..16].to_string()
+            }
+        };
+
+        let mut timestamps: Vec<(String, OrgTimestamp)> = Vec::new();
+        if let Some(scheduled) = section.scheduled {
+            timestamps.push((format!("{base_id}:scheduled"), scheduled));
+        }
+        if let Some(deadline) = section.deadline {
+            timestamps.push((format!("{base_id}:deadline"), deadline));
+        }
+        let active = headline.timestamps.iter().chain(&section.timestamps);
+        timestamps.extend(active.enumerate().map(|(i, timestamp)| (format!("{base_id}:{i}"), *timestamp)));
+
+        for (event_id, timestamp) in timestamps {
+            let Some((start_time, end_time, all_day)) = timestamp.times() else {
+                continue;
+            };
+            let mut event = CalendarEvent::new_local(headline.title.clone(), start_time, end_time, calendar_id.to_string());
+            event.event_id = event_id;
+            event.all_day = all_day;
+            event.description = section.description.clone();
+            event.source_type = SourceType::OrgMode;
+            event.updated = false;
+            events.push(OrgEvent {
+                event,
+                tags: tags.clone(),
+                line_start: start + 1,
+                line_end: end,
+                target_calendar_id: None,
+            });
+        }
+    }
+    events
+}
+
+/// Number of leading stars if the line is a headline
+fn headline_level(line: &str) -> Option<usize> {
+    let level = line.chars().take_while(|&c| c == '*').count();
+    (level > 0 && line[level..].starts_with(' ')).then_some(level)
+}
+
+/// Headline text after the stars, without TODO keyword, priority, tags and timestamps
+struct Headline {
+    title: String,
+    tags: Vec<String>,
+    timestamps: Vec<OrgTimestamp>,
+}
+
+impl Headline {
+    fn parse(text: &str) -> Self {
+        let mut text = text.trim();
+        if let Some((keyword, rest)) = text.split_once(' ') && TODO_KEYWORDS.contains(&keyword) {
+            text = rest.trim_start();
+        }
+        if text.starts_with("[#") && text.get(3..4) == Some("]") {
+            text = text[4..].trim_start();
+        }
+        let mut tags = Vec::new();
+        if let Some((rest, last)) = text.rsplit_once(char::is_whitespace)
+            && last.len() > 1 && last.starts_with(':') && last.ends_with(':') {
+            tags = last.trim_matches(':').split(':').map(str::to_string).collect();
+            text = rest.trim_end();
+        }
+        let (timestamps, title) = active_timestamps(text);
+        Self {
+            title: title.split_whitespace().collect::<Vec<_>>().join(" "),
+            tags,
+            timestamps,
+        }
+    }
+}
+
+/// Body of a headline up to the next headline
+struct Section {
+    scheduled: Option<OrgTimestamp>,
+    deadline: Option<OrgTimestamp>,
+    id: Option<String>,
+    timestamps: Vec<OrgTimestamp>,
+    timestamp_lines: Vec<usize>, // Lines searched for active timestamps, excluding planning and drawers
+    description: Option<String>,
+}
+
+impl Section {
+    fn parse(lines: &[&str]) -> Self {
+        let mut section = Self { scheduled: None, deadline: None, id: None, timestamps: Vec::new(), timestamp_lines: Vec::new(), description: None };
+        let mut body: Vec<&str> = Vec::new();
+        let mut in_drawer = false;
+        for (i, line) in lines.iter().enumerate() {
+            let trimmed = line.trim();
+            if i == 0 && is_planning_line(trimmed) {
+                section.scheduled = planning_timestamp(trimmed, "SCHEDULED:");
+                section.deadline = planning_timestamp(trimmed, "DEADLINE:");
+            } else if in_drawer {
+                if trimmed.eq_ignore_ascii_case(":END:") {
+                    in_drawer = false;
+                } else if let Some(id) = trimmed.strip_prefix(":ID:") {
+                    section.id = Some(id.trim().to_string()).filter(|id| !id.is_empty());
+                }
+            } else if trimmed.eq_ignore_ascii_case(":PROPERTIES:") || trimmed.eq_ignore_ascii_case(":LOGBOOK:") {
+                in_drawer = true;
+            } else {
+                section.timestamps.extend(active_timestamps(line).0);
+                section.timestamp_lines.push(i);
+                body.push(line);
+            }
+        }
+        let description = body.join("\n").trim().to_string();
+        section.description = Some(description).filter(|description| !description.is_empty());
+        section
+    }
+}
+
+fn is_planning_line(line: &str) -> bool {
+    ["SCHEDULED:", "DEADLINE:", "CLOSED:"].iter().any(|keyword| line.starts_with(keyword))
+}
+
+/// Active timestamp following `keyword` on a planning line
+fn planning_timestamp(line: &str, keyword: &str) -> Option<OrgTimestamp> {
+    let (_, rest) = line.split_once(keyword)?;
+    let rest = rest.trim_start();
+    if !rest.starts_with('<') {
+        return None;
+    }
+    active_timestamps(rest).0.into_iter().next()
+}
+
+/// Every active timestamp in `text`, and the text with them removed. Inactive [timestamps] are
+/// left alone, since org doesn't show them in the agenda.
+fn active_timestamps(text: &str) -> (Vec<OrgTimestamp>, String) {
+    let spans = timestamp_spans(text);
+    let mut rest_text = String::new();
+    let mut last = 0;
+    for (span, _) in &spans {
+        rest_text.push_str(&text[last..span.start]);
+        last = span.end;
+    }
+    rest_text.push_str(&text[last..]);
+    (spans.into_iter().map(|(_, timestamp)| timestamp).collect(), rest_text)
+}
+
+/// Byte ranges of the active timestamps in `text`, a <start>--<end> range counting as one
+fn timestamp_spans(text: &str) -> Vec<(Range<usize>, OrgTimestamp)> {
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    while let Some(open) = text[pos..].find('<').map(|open| pos + open) {
+        let Some(close) = text[open..].find('>').map(|close| open + close) else {
+            break;
+        };
+        pos = close + 1;
+        let Some(mut timestamp) = parse_timestamp(&text[open + 1..close]) else {
+            continue;
+        };
+        if let Some(range) = text[pos..].strip_prefix("--<")
+            && let Some(close) = range.find('>')
+            && let Some(end) = parse_timestamp(&range[..close]) {
+            timestamp.end_date = Some(end.date);
+            timestamp.end = end.start;
+            pos += 3 + close + 1;
+        }
+        spans.push((open..pos, timestamp));
+    }
+    spans
+}
+
+/// Parse the inside of <2025-06-02 Mon 09:00-10:00 +1w>. Day names, repeaters and warning
+/// delays are ignored.
+// TODO turn repeaters into recurring series
+fn parse_timestamp(text: &str) -> Option<OrgTimestamp> {
+    let mut parts = text.split_whitespace();
+    let date = NaiveDate::parse_from_str(parts.next()?, "%Y-%m-%d").ok()?;
+    let mut timestamp = OrgTimestamp { date, start: None, end: None, end_date: None };
+    let time = |text: &str| NaiveTime::parse_from_str(text, "%H:%M").ok();
+    for part in parts {
+        if let Some((start, end)) = part.split_once('-')
+            && let (Some(start), Some(end)) = (time(start), time(end)) {
+            (timestamp.start, timestamp.end) = (Some(start), Some(end));
+        } else if let Some(start) = time(part) {
+            timestamp.start = Some(start);
+        }
+    }
+    Some(timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AGENDA: &str = "#+TITLE: Agenda
+* Work :job:
+** TODO [#A] Quarterly review <2025-06-02 Mon 09:00-10:30> :meeting:
+Prepare slides.
+** DONE Submit report
+   DEADLINE: <2025-06-05 Thu> SCHEDULED: <2025-06-03 Tue 14:00>
+   :PROPERTIES:
+   :ID:       report-42
+   :END:
+** Notes [2025-06-01 Sun]
+Nothing scheduled here.
+* Conference
+<2025-06-10 Tue>--<2025-06-12 Thu>
+";
+
+    #[test]
+    fn parses_timestamps_scheduled_and_deadline() {
+        let events = parse_org(AGENDA, "agenda.org");
+        let summary: Vec<(&str, &str, usize, usize)> = events.iter()
+            .map(|org| (org.event.title.as_str(), org.event.event_id.split(':').next_back().unwrap(), org.line_start, org.line_end))
+            .collect();
+        assert_eq!(summary, [
+            ("Quarterly review", "0", 3, 4),
+            ("Submit report", "scheduled", 5, 9),
+            ("Submit report", "deadline", 5, 9),
+            ("Conference", "0", 12, 13),
+        ]);
+
+        let review = &events[0];
+        assert_eq!(review.tags, ["job", "meeting"]);
+        assert_eq!(review.event.description.as_deref(), Some("Prepare slides."));
+        assert_eq!(review.event.end_time - review.event.start_time, TimeDelta::minutes(90));
+        assert_eq!(review.event.source_type, SourceType::OrgMode);
+        assert!(!review.event.updated);
+
+        assert_eq!(events[1].event.event_id, "report-42:scheduled");
+        assert!(!events[1].event.all_day);
+        assert!(events[2].event.all_day);
+        assert_eq!(events[2].event.description, None);
+
+        let day = |d| NaiveDate::from_ymd_opt(2025, 6, d).unwrap();
+        assert_eq!(events[3].event.all_day_dates(), Some((day(10), day(12))));
+    }
+
+    fn rewrite(content: &str, event_id: &str, edit: impl FnOnce(&mut CalendarEvent)) -> String {
+        let org = parse_org(content, "agenda.org").into_iter().find(|org| org.event.event_id.ends_with(event_id)).unwrap();
+        let location = OrgLocation {
+            path: "agenda.org".to_string(),
+            line_start: org.line_start,
+            line_end: org.line_end,
+            content_hash: content_hash(content),
+        };
+        let mut event = org.event;
+        edit(&mut event);
+        rewrite_entry(content, &location, &event).unwrap()
+    }
+
+    #[test]
+    fn write_back_only_touches_headline_and_timestamp() {
+        let content = AGENDA.replace('\n', "\r\n");
+        let moved = rewrite(&content, "0", |event| {
+            event.title = "Annual review".to_string();
+            event.start_time += TimeDelta::days(1);
+            event.end_time += TimeDelta::days(1) + TimeDelta::minutes(30);
+        });
+        assert_eq!(moved, content.replace(
+            "Quarterly review <2025-06-02 Mon 09:00-10:30> :meeting:",
+            "Annual review <2025-06-03 Tue 09:00-11:00> :meeting:"));
+
+        let rescheduled = rewrite(AGENDA, "scheduled", |event| {
+            event.start_time += TimeDelta::hours(2);
+            event.end_time += TimeDelta::hours(2);
+        });
+        assert_eq!(rescheduled, AGENDA.replace("SCHEDULED: <2025-06-03 Tue 14:00>", "SCHEDULED: <2025-06-03 Tue 16:00>"));
+
+        let repeating = "* Gym\n<2025-06-02 Mon +1w>\n";
+        let extended = rewrite(repeating, "0", |event| event.end_time += TimeDelta::days(1));
+        assert_eq!(extended, "* Gym\n<2025-06-02 Mon +1w>--<2025-06-03 Tue>\n");
+    }
+
+    #[test]
+    fn ids_stay_the_same_when_lines_move() {
+        let moved = format!("* Inbox\nSomething new\n{AGENDA}");
+        let ids = |content: &str| parse_org(content, "agenda.org").into_iter().map(|org| org.event.event_id).collect::<Vec<_>>();
+        assert_eq!(ids(AGENDA), ids(&moved));
+        assert_ne!(content_hash(AGENDA), content_hash(&moved));
+    }
+
+    #[test]
+    fn save_refuses_to_clobber_external_edits() {
+        let path = std::env::temp_dir().join(format!("ultima-{}.org", crate::event::generate_event_id()));
+        fs::write(&path, AGENDA).unwrap();
+        let db = Mutex::new(Database::new(":memory:").unwrap());
+        let mirrors = OrgMirrors::default();
+        assert!(import_org_file(&db, &path, &mirrors).unwrap());
+        assert!(!import_org_file(&db, &path, &mirrors).unwrap());
+
+        let day = NaiveDate::from_ymd_opt(2025, 6, 2).unwrap();
+        let mut event = db.lock().unwrap().events_on_day(day).unwrap().remove(0);
+        event.title = "Annual review".to_string();
+        save_org_event(&db, &event, &mirrors).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), AGENDA.replace("Quarterly review", "Annual review"));
+        // Renaming changes the ID of a headline without an :ID: property
+        let mut event = db.lock().unwrap().events_on_day(day).unwrap().remove(0);
+        assert_eq!(event.title, "Annual review");
+
+        let external = AGENDA.replace("Prepare slides.", "Prepare slides and notes.");
+        fs::write(&path, &external).unwrap();
+        event.title = "Stale edit".to_string();
+        assert!(save_org_event(&db, &event, &mirrors).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), external);
+        assert_eq!(db.lock().unwrap().events_on_day(day).unwrap()[0].title, "Quarterly review");
+        fs::remove_file(&path).unwrap();
+    }
+}
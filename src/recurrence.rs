@@ -0,0 +1,74 @@
+use chrono::{DateTime, NaiveTime, TimeDelta, Utc};
+use rrule::{RRuleSet, Tz};
+
+use crate::event::CalendarEvent;
+
+/// Most occurrences one series can produce for a single query
+const MAX_OCCURRENCES: u16 = 5000;
+
+/// Occurrences of a recurring series that start before `end` and end after `start`, built from
+/// the master event's RRULE, EXDATE and RDATE lines. Each occurrence gets the ID Google Calendar
+/// uses for that instance, so edits and deletions line up with the remote series.
+/// A master whose rules can't be parsed is returned as a single event.
+pub fn occurrences(master: &CalendarEvent, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<CalendarEvent> {
+    let overlaps = |event: &CalendarEvent| event.start_time < end && event.end_time > start;
+    let Ok(rule_set) = rule_set(master).parse::<RRuleSet>() else {
+        return Some(master.clone()).filter(overlaps).into_iter().collect();
+    };
+
+    let duration = master.end_time - master.start_time;
+    // All-day dates are expanded in local time, a day of margin covers every offset
+    let after = (start - duration - TimeDelta::days(1)).with_timezone(&Tz::UTC);
+    let before = (end + TimeDelta::days(1)).with_timezone(&Tz::UTC);
+    rule_set.after(after).before(before).all(MAX_OCCURRENCES).dates.into_iter()
+        .map(|date| {
+            let start_time = if master.all_day {
+                date.date_naive().and_time(NaiveTime::MIN).and_utc()
+            } else {
+                date.to_utc()
+            };
+            occurrence(master, start_time, duration)
+        })
+        .filter(overlaps)
+        .collect()
+}
+
+/// ID Google Calendar gives the occurrence of a series starting at `start_time`
+pub fn instance_id(series_id: &str, start_time: DateTime<Utc>, all_day: bool) -> String {
+    if all_day {
+        format!("{series_id}_{}", start_time.format("%Y%m%d"))
+    } else {
+        format!("{series_id}_{}", start_time.format("%Y%m%dT%H%M%SZ"))
+    }
+}
+
+fn occurrence(master: &CalendarEvent, start_time: DateTime<Utc>, duration: TimeDelta) -> CalendarEvent {
+    CalendarEvent {
+        event_id: instance_id(&master.event_id, start_time, master.all_day),
+        start_time,
+        end_time: start_time + duration,
+        updated: false,
+        upload_error: None,
+        recurrence: Vec::new(),
+        recurring_event_id: Some(master.event_id.clone()),
+        original_start_time: Some(start_time),
+        ..master.clone()
+    }
+}
+
+/// iCalendar text for the series: DTSTART in the series' time zone so that occurrences keep their
+/// wall clock time across DST changes, followed by the stored recurrence lines
+fn rule_set(master: &CalendarEvent) -> String {
+    let time_zone = master.time_zone.as_deref()
+        .and_then(|name| name.parse::<chrono_tz::Tz>().ok());
+    let mut text = match time_zone {
+        _ if master.all_day => format!("DTSTART;VALUE=DATE:{}", master.start_time.format("%Y%m%d")),
+        Some(time_zone) => format!("DTSTART;TZID={}:{}", time_zone.name(), master.start_time.with_timezone(&time_zone).format("%Y%m%dT%H%M%S")),
+        None => format!("DTSTART:{}", master.start_time.format("%Y%m%dT%H%M%SZ")),
+    };
+    for line in &master.recurrence {
+        text.push('\n');
+        text.push_str(line);
+    }
+    text
+}
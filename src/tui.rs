@@ -1,48 +1,286 @@
-use std::{io, vec};
+use std::{collections::{HashMap, HashSet}, io, sync::{Arc, Mutex}, time::Duration, vec};
 use num_traits::cast::FromPrimitive;
 
 use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
 use ratatui::{
     buffer::Buffer,
-    layout::{Constraint, Layout, Rect},
+    layout::{Constraint, Flex, Layout, Rect},
     style::{Color, Stylize},
     symbols::border,
     text::{Line, Span, Text},
-    widgets::{Block, Borders, Paragraph, Widget},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Widget, Wrap},
     DefaultTerminal, Frame,
 };
-use chrono::{Datelike, Days, Month, Months, NaiveDate, TimeDelta, Weekday};
+use chrono::{Datelike, Days, Local, Month, Months, NaiveDate, TimeDelta, Weekday};
+use tokio::sync::{watch, Notify};
 
-//TODO refactor out separate calendar stuff, so make separate year, month, and weekly views
+use crate::{
+    application_state::{StateHandle, SyncStatus},
+    calendar_list::{CalendarList, CalendarListAction},
+    config::{Action, Config, Keybindings, Theme},
+    conflict_view::{ConflictAction, ConflictView},
+    database::Database,
+    error::UltimaError,
+    event::{local_day_start, CalendarEvent, GcalCalendar, SourceType},
+    event_form::{EventForm, FormAction},
+    month_view::{month_grid, MonthView},
+    org::{org_calendar, save_org_event, OrgMirrors},
+    week_view::{first_day_of_week, WeekView},
+};
+
+//TODO move the year strip (DayDensity, get_column, build_calendar) into a year_view module like
+// week_view and month_view
+
+/// Layout of the calendar next to the agenda
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum View {
+    Year, // Strip of every day of the year below the agenda
+    Week, // Hourly grid of the selected date's week
+    Month, // Grid of the selected date's month with the titles of each day
+}
+
+/// How long to wait for a key press before checking whether the background tasks changed anything
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
 
-#[derive(Debug, Default)]
 pub struct CalendarTextUserInterface {
+    db: Arc<Mutex<Database>>,
+    upload_trigger: Arc<Notify>,
+    sync_trigger: Arc<Notify>,
+    sync_status: watch::Receiver<SyncStatus>,
+    changes: watch::Receiver<()>,
     current_date: NaiveDate,
     selected_date: NaiveDate,
     width: u16,
     selected_column: u16,
     saved_column: u16,
+    density: HashMap<NaiveDate, DayDensity>,
+    calendars: HashMap<String, GcalCalendar>,
+    org_calendars: HashMap<String, GcalCalendar>, // Stand-ins for the org files events were imported from
+    org_mirrors: OrgMirrors,
+    keys: Keybindings,
+    theme: Theme,
+    week_start: Weekday,
+    view: View,
+    view_events: Vec<CalendarEvent>, // Events shown by the week or month view
+    loaded_range: Option<(NaiveDate, NaiveDate)>, // Dates view_events were loaded for
+    calendar_colors: HashMap<String, Color>,
+    loaded_year: Option<i32>,
+    agenda: Vec<CalendarEvent>,
+    agenda_state: ListState,
+    loaded_day: Option<NaiveDate>,
+    agenda_day: Option<NaiveDate>, // Date the agenda holds the events of, kept when invalidated
+    show_detail: bool,
+    form: Option<EventForm>,
+    confirm_delete: bool,
+    conflicted: HashSet<(String, String)>, // (event_id, calendar_id) of events with sync conflicts
+    conflict_view: Option<ConflictView>,
+    calendar_list: Option<CalendarList>,
+    status: Option<String>, // Message about the last key press, cleared by the next one
     exit: bool,
 }
 
+/// Summary of a single day's events, used to shade the year grid
+#[derive(Debug, Default)]
+struct DayDensity {
+    count: usize,
+    busy: HashMap<String, TimeDelta>, // Busy time per calendar_id
+}
+
+impl DayDensity {
+    /// Calendar with the most busy time on this day
+    fn busiest_calendar(&self) -> Option<&str> {
+        self.busy.iter()
+            .max_by(|(a_id, a), (b_id, b)| a.cmp(b).then(b_id.cmp(a_id)))
+            .map(|(id, _)| id.as_str())
+    }
+
+    fn glyph(&self) -> char {
+        match self.count {
+            0 => ' ',
+            1 => '·',
+            2..=3 => '•',
+            _ => '●',
+        }
+    }
+}
+
 impl CalendarTextUserInterface {
-    pub fn new(initial_date: NaiveDate) -> Self {
-        let current_date = initial_date.clone();
-        let selected_date = initial_date.clone();
+    pub fn new(initial_date: NaiveDate, state: StateHandle, config: &Config) -> Self {
+        let StateHandle { db, upload_trigger, sync_trigger, sync_status, changes } = state;
+        let current_date = initial_date;
+        let selected_date = initial_date;
         let width = 37;
         let selected_column = Self::get_column(selected_date, width);
         let saved_column = selected_column;
+        let density = HashMap::new();
+        let calendars = HashMap::new();
+        let org_calendars = HashMap::new();
+        let org_mirrors = config.org.mirrors.clone();
+        let keys = config.keybindings.clone();
+        let theme = config.theme;
+        let week_start = config.week_start;
+        let view = View::Year;
+        let view_events = Vec::new();
+        let loaded_range = None;
+        let calendar_colors = HashMap::new();
+        let loaded_year = None;
+        let agenda = Vec::new();
+        let agenda_state = ListState::default();
+        let loaded_day = None;
+        let agenda_day = None;
+        let show_detail = false;
+        let form = None;
+        let confirm_delete = false;
+        let conflicted = HashSet::new();
+        let conflict_view = None;
+        let calendar_list = None;
+        let status = None;
         let exit = false;
         Self {
+            db,
+            upload_trigger,
+            sync_trigger,
+            sync_status,
+            changes,
             current_date,
             selected_date,
             selected_column,
             width,
             saved_column,
+            density,
+            calendars,
+            org_calendars,
+            org_mirrors,
+            keys,
+            theme,
+            week_start,
+            view,
+            view_events,
+            loaded_range,
+            calendar_colors,
+            loaded_year,
+            agenda,
+            agenda_state,
+            loaded_day,
+            agenda_day,
+            show_detail,
+            form,
+            confirm_delete,
+            conflicted,
+            conflict_view,
+            calendar_list,
+            status,
             exit
         }
     }
 
+    /// Force the year grid and agenda to be reloaded from the database before the next draw
+    fn invalidate(&mut self) {
+        self.loaded_year = None;
+        self.loaded_day = None;
+        self.loaded_range = None;
+    }
+
+    /// Show an error that stopped something from working in the status bar instead of crashing
+    pub fn report_error(&mut self, error: UltimaError) {
+        self.status = Some(error.to_string());
+    }
+
+    /// Reload the year grid's event density from the database if the selected year changed
+    fn load_year(&mut self) {
+        let year = self.selected_date.year();
+        if self.loaded_year == Some(year) {
+            return;
+        }
+
+        let start = NaiveDate::from_ymd_opt(year, 1, 1).unwrap();
+        let end = NaiveDate::from_ymd_opt(year, 12, 31).unwrap();
+        let db = self.db.lock().unwrap();
+        let loaded = db.events_in_range(start, end)
+            .and_then(|events| Ok((events, db.calendars()?, db.conflicts()?)));
+        drop(db);
+        let (events, calendars, conflicts) = match loaded {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                self.report_error(error.into());
+                self.loaded_year = Some(year); // Retried once something is saved or synced
+                return;
+            }
+        };
+
+        self.conflicted = conflicts.into_iter()
+            .map(|conflict| (conflict.local.event_id, conflict.local.calendar_id))
+            .collect();
+        self.calendar_colors = calendars.iter()
+            .filter_map(|calendar| Some((calendar.id.clone(), parse_hex_color(calendar.color.as_deref()?)?)))
+            .collect();
+        self.calendars = calendars.into_iter()
+            .map(|calendar| (calendar.id.clone(), calendar))
+            .collect();
+        for event in events.iter().filter(|event| event.source_type == SourceType::OrgMode) {
+            self.org_calendars.entry(event.calendar_id.clone()).or_insert_with(|| org_calendar(&event.calendar_id));
+        }
+
+        self.density.clear();
+        for event in events {
+            let (event_start, event_end) = event.local_span();
+            let mut day = event_start.date_naive().max(start);
+            // An event ending exactly at midnight doesn't occupy the following day
+            let last_day = (event_end - TimeDelta::seconds(1)).date_naive().max(day).min(end);
+            while day <= last_day {
+                let day_start = local_day_start(day);
+                let day_end = day_start + TimeDelta::days(1);
+                // All-day events add to the count but not to the busy time
+                let busy = if event.all_day { TimeDelta::zero() } else { event_end.min(day_end) - event_start.max(day_start) };
+
+                let density = self.density.entry(day).or_default();
+                density.count += 1;
+                *density.busy.entry(event.calendar_id.clone()).or_default() += busy.max(TimeDelta::zero());
+                day = day + Days::new(1);
+            }
+        }
+        self.loaded_year = Some(year);
+    }
+
+    /// Reload the agenda pane's events if the selected date changed
+    fn load_day(&mut self) {
+        if self.loaded_day == Some(self.selected_date) {
+            return;
+        }
+
+        let result = self.db.lock().unwrap().events_on_day(self.selected_date);
+        let events = match result {
+            Ok(events) => events,
+            Err(error) => {
+                self.report_error(error.into());
+                self.agenda.clear();
+                self.loaded_day = Some(self.selected_date);
+                return;
+            }
+        };
+        // All-day events are listed first as banners, then events lasting the whole day
+        let mut agenda = events;
+        agenda.sort_by_key(|event| (!event.all_day, !covers_whole_day(event, self.selected_date)));
+
+        // Reloading the same day keeps the selected event, which the detail popup and the delete
+        // prompt act on. If it is gone they are closed rather than moved to another event.
+        let selected = self.agenda_state.selected().and_then(|i| self.agenda.get(i))
+            .filter(|_| self.agenda_day == Some(self.selected_date))
+            .map(|event| (event.event_id.clone(), event.calendar_id.clone()));
+        let kept = selected.as_ref().and_then(|(event_id, calendar_id)| {
+            agenda.iter().position(|event| event.event_id == *event_id && event.calendar_id == *calendar_id)
+        });
+        if selected.is_some() && kept.is_none() {
+            self.show_detail = false;
+            self.confirm_delete = false;
+        }
+
+        self.agenda = agenda;
+        self.agenda_state.select(kept.or(if self.agenda.is_empty() { None } else { Some(0) }));
+        self.loaded_day = Some(self.selected_date);
+        self.agenda_day = Some(self.selected_date);
+    }
+
     fn get_column(date: NaiveDate, width: u16) -> u16 {
         let day = date.day();
         let month = date.month();
@@ -58,26 +296,140 @@ impl CalendarTextUserInterface {
         width - weekday_offset - month_num_days + (day as u16)
     }
 
+    /// Dates shown by the current view, None for the year view which only needs the density
+    fn view_range(&self) -> Option<(NaiveDate, NaiveDate)> {
+        match self.view {
+            View::Year => None,
+            View::Week => {
+                let start = first_day_of_week(self.selected_date, self.week_start);
+                Some((start, start + Days::new(6)))
+            }
+            View::Month => Some(month_grid(self.selected_date, self.week_start)),
+        }
+    }
+
+    /// Reload the events of the week or month view from the database if it moved to other dates
+    fn load_view(&mut self) {
+        let Some((start, end)) = self.view_range() else {
+            return;
+        };
+        if self.loaded_range == Some((start, end)) {
+            return;
+        }
+
+        let result = self.db.lock().unwrap().events_in_range(start, end);
+        match result {
+            Ok(events) => self.view_events = events,
+            Err(error) => {
+                self.view_events.clear();
+                self.report_error(error.into());
+            }
+        }
+        self.loaded_range = Some((start, end));
+    }
+
+    fn toggle_view(&mut self) {
+        self.view = match self.view {
+            View::Year => View::Week,
+            View::Week => View::Month,
+            View::Month => View::Year,
+        };
+    }
+
+    /// State of the background sync next to the title, with the time it last succeeded
+    fn sync_indicator(&self) -> Span<'static> {
+        let last_synced = self.last_synced();
+        match &*self.sync_status.borrow() {
+            SyncStatus::Idle => format!("[{last_synced}]").dark_gray(),
+            SyncStatus::Syncing => format!("[syncing, {last_synced}]").blue(),
+            SyncStatus::Offline => format!("[offline, {last_synced}]").yellow(),
+            SyncStatus::Error(error) => format!("[sync failed: {error}]").red(),
+        }
+    }
+
+    /// When the events of an enabled calendar were last fetched, so that it's clear how fresh the
+    /// cached data is while offline
+    fn last_synced(&self) -> String {
+        let last_sync_time = self.calendars.values()
+            .filter(|calendar| calendar.sync_enabled)
+            .filter_map(|calendar| calendar.last_sync_time)
+            .max()
+            .map(|time| time.with_timezone(&Local));
+        match last_sync_time {
+            Some(time) if time.date_naive() == Local::now().date_naive() => format!("last synced {}", time.format("%H:%M")),
+            Some(time) => format!("last synced {}", time.format("%Y-%m-%d %H:%M")),
+            None => "never synced".to_string(),
+        }
+    }
+
     /// runs the application's main loop until the user quits
     pub fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
         while !self.exit {
+            if self.changes.has_changed().unwrap_or(false) {
+                self.changes.mark_unchanged();
+                self.invalidate();
+            }
+            self.load_year();
+            self.load_day();
+            self.load_view();
             terminal.draw(|frame| self.draw(frame))?;
             self.handle_events()?;
         }
         Ok(())
     }
 
-    fn draw(&self, frame: &mut Frame) {
+    fn draw(&mut self, frame: &mut Frame) {
         use Constraint::{Fill, Length, Min};
 
-        let vertical = Layout::vertical([Length(1), Min(0), Length(15)]);
+        let calendar_height = if self.view == View::Year { 15 } else { 0 };
+        let vertical = Layout::vertical([Length(1), Min(0), Length(calendar_height)]);
         let [title_area, main_area, calendar_vertical] = vertical.areas(frame.area());
-        let horizontal = Layout::horizontal([Fill(1); 2]);
+        let horizontal = match self.view {
+            View::Year => Layout::horizontal([Fill(1); 2]),
+            View::Week | View::Month => Layout::horizontal([Fill(1), Fill(2)]),
+        };
         let calendar_horizontal = Layout::horizontal([Min(128), Min(12)]);
         let [left_area, right_area] = horizontal.areas(main_area);
         let [calendar, date] = calendar_horizontal.areas(calendar_vertical);
 
-        frame.render_widget(Block::bordered().title("[ultima forsan]"), title_area);
+        let mut title_block = Block::bordered().title("[ultima forsan]").title(self.sync_indicator());
+        if !self.conflicted.is_empty() {
+            let notice = match self.conflicted.len() {
+                1 => "[1 sync conflict]".to_string(),
+                count => format!("[{count} sync conflicts]"),
+            };
+            title_block = title_block.title(Line::from(notice.red().bold()).centered());
+        }
+        if let Some(status) = &self.status {
+            title_block = title_block.title(Line::from(format!("[{status}]")).right_aligned());
+        }
+        frame.render_widget(title_block, title_area);
+
+        //TODO maybe tasks on left and image on right?
+        let agenda = self.build_agenda();
+        frame.render_stateful_widget(agenda, left_area, &mut self.agenda_state);
+
+        match self.view {
+            View::Year => self.draw_year(frame, calendar, date, right_area),
+            View::Week => {
+                let block = self.view_block(format!(" [week of {}] ", first_day_of_week(self.selected_date, self.week_start).format("%Y-%m-%d")));
+                let week = WeekView::new(self.selected_date, self.week_start, &self.view_events, &self.calendar_colors, self.theme);
+                frame.render_widget(&week, block.inner(right_area));
+                frame.render_widget(block, right_area);
+            }
+            View::Month => {
+                let block = self.view_block(format!(" [{}] ", self.selected_date.format("%B %Y")));
+                let month = MonthView::new(self.selected_date, self.week_start, &self.view_events, &self.calendar_colors, self.theme);
+                frame.render_widget(&month, block.inner(right_area));
+                frame.render_widget(block, right_area);
+            }
+        }
+
+        self.draw_popups(frame);
+    }
+
+    /// Year strip with the selected date next to it, below the agenda
+    fn draw_year(&self, frame: &mut Frame, calendar: Rect, date: Rect, right_area: Rect) {
         frame.render_widget(self, calendar);
 
         let date_block = Block::bordered()
@@ -91,14 +443,85 @@ impl CalendarTextUserInterface {
             .block(date_block);
 
         frame.render_widget(date_paragraph, date);
-
-        //TODO maybe tasks on left and image on right?
-        frame.render_widget(Block::bordered().title("Left"), left_area);
         frame.render_widget(Block::bordered().title("Right"), right_area);
     }
 
-    /// updates the application's state based on user input
+    /// Border of the week and month views, with the keys that move through them
+    fn view_block(&self, title: String) -> Block<'static> {
+        let keys = &self.keys;
+        let (step, next_view) = match self.view {
+            View::Month => (" Month ", " Year view "),
+            _ => (" Week ", " Month view "),
+        };
+        let instructions = Line::from(vec![
+            " Day ".into(),
+            hint(keys.back),
+            hint(keys.forward),
+            step.into(),
+            hint(keys.up),
+            hint(keys.down),
+            " Today ".into(),
+            hint(keys.today),
+            next_view.into(),
+            hint(keys.view),
+            " Quit ".into(),
+            hint(keys.quit),
+            " ".into(),
+        ]);
+        Block::bordered()
+            .title(Line::from(title.bold()).centered())
+            .title_bottom(instructions.centered())
+            .border_set(border::THICK)
+    }
+
+    fn draw_popups(&mut self, frame: &mut Frame) {
+        if self.show_detail
+            && let Some(event) = self.agenda_state.selected().and_then(|i| self.agenda.get(i)) {
+            let popup_area = centered(frame.area(), 60, 60);
+            frame.render_widget(Clear, popup_area);
+            frame.render_widget(self.build_detail(event), popup_area);
+        }
+
+        if let Some(form) = &self.form {
+            let popup_area = centered(frame.area(), 60, 40);
+            frame.render_widget(Clear, popup_area);
+            frame.render_widget(form, popup_area);
+        }
+
+        if let Some(conflict_view) = &self.conflict_view {
+            let popup_area = centered(frame.area(), 80, 50);
+            frame.render_widget(Clear, popup_area);
+            frame.render_widget(conflict_view, popup_area);
+        }
+
+        if let Some(calendar_list) = &mut self.calendar_list {
+            let popup_area = centered(frame.area(), 50, 50);
+            frame.render_widget(Clear, popup_area);
+            frame.render_widget(calendar_list, popup_area);
+        }
+
+        if self.confirm_delete
+            && let Some(event) = self.agenda_state.selected().and_then(|i| self.agenda.get(i)) {
+            let popup_area = centered(frame.area(), 40, 10);
+            let prompt = Paragraph::new(Line::from(vec![
+                format!("Delete \"{}\"? ", event.title).into(),
+                "<y>".blue().bold(),
+                "/".into(),
+                "<n>".blue().bold(),
+            ]))
+                .centered()
+                .block(Block::bordered().border_set(border::THICK));
+            frame.render_widget(Clear, popup_area);
+            frame.render_widget(prompt, popup_area);
+        }
+    }
+
+    /// updates the application's state based on user input. Returns without one after
+    /// `POLL_INTERVAL` so that data synced in the background gets drawn.
     fn handle_events(&mut self) -> io::Result<()> {
+        if !event::poll(POLL_INTERVAL)? {
+            return Ok(());
+        }
         match event::read()? {
             Event::Key(key_event) if key_event.kind == KeyEventKind::Press => {
                 self.handle_key_event(key_event)
@@ -109,19 +532,207 @@ impl CalendarTextUserInterface {
     }
 
     fn handle_key_event(&mut self, key_event: KeyEvent) {
-        match key_event.code {
-            KeyCode::Char('q') => self.exit(),
-            KeyCode::Char('h') => self.back(),
-            KeyCode::Char('j') => self.down(),
-            KeyCode::Char('k') => self.up(),
-            KeyCode::Char('l') => self.forward(),
-            KeyCode::Char('u') => self.back_year(),
-            KeyCode::Char('d') => self.forward_year(),
-            KeyCode::Char('t') => self.set_date(self.current_date),
-            _ => {}
+        self.status = None;
+        if let Some(form) = &mut self.form {
+            match form.handle_key_event(key_event) {
+                FormAction::None => {}
+                FormAction::Cancel => self.form = None,
+                FormAction::Save(event) => self.save_event(*event),
+            }
+            return;
+        }
+
+        if let Some(conflict_view) = &mut self.conflict_view {
+            match conflict_view.handle_key_event(key_event) {
+                ConflictAction::None => {}
+                ConflictAction::Close => self.conflict_view = None,
+                ConflictAction::Resolve { event, remote_etag, upload } => self.resolve_conflict(*event, &remote_etag, upload),
+            }
+            return;
+        }
+
+        if let Some(calendar_list) = &mut self.calendar_list {
+            match calendar_list.handle_key_event(key_event) {
+                CalendarListAction::None => {}
+                CalendarListAction::Close => {
+                    // Fetch the events of calendars that were just checked
+                    self.calendar_list = None;
+                    self.sync_trigger.notify_one();
+                }
+                CalendarListAction::Toggle { calendar_id, enabled } => self.set_calendar_enabled(&calendar_id, enabled),
+            }
+            return;
+        }
+
+        if self.confirm_delete {
+            if key_event.code == KeyCode::Char('y') {
+                self.delete_selected_event();
+            }
+            self.confirm_delete = false;
+            return;
+        }
+
+        if self.show_detail {
+            if matches!(key_event.code, KeyCode::Enter | KeyCode::Esc | KeyCode::Char('q')) {
+                self.show_detail = false;
+            }
+            return;
+        }
+
+        let action = match key_event.code {
+            KeyCode::Char(key) => self.keys.action(key),
+            KeyCode::Down => Some(Action::NextEvent),
+            KeyCode::Up => Some(Action::PreviousEvent),
+            KeyCode::Enter => {
+                self.show_detail = self.agenda_state.selected().is_some();
+                None
+            }
+            _ => None,
+        };
+        match action {
+            Some(Action::Quit) => self.exit(),
+            Some(Action::Back) => self.back(),
+            Some(Action::Down) if self.view == View::Week => self.move_days(7),
+            Some(Action::Up) if self.view == View::Week => self.move_days(-7),
+            Some(Action::Down) => self.down(),
+            Some(Action::Up) => self.up(),
+            Some(Action::Forward) => self.forward(),
+            Some(Action::BackYear) => self.back_year(),
+            Some(Action::ForwardYear) => self.forward_year(),
+            Some(Action::Today) => self.set_date(self.current_date),
+            Some(Action::NextEvent) => self.agenda_state.select_next(),
+            Some(Action::PreviousEvent) => self.agenda_state.select_previous(),
+            Some(Action::Add) => self.new_event(),
+            Some(Action::Edit) => self.edit_selected_event(),
+            Some(Action::Delete) => self.ask_delete(),
+            Some(Action::Conflicts) => self.show_conflicts(),
+            Some(Action::Calendars) => self.show_calendar_list(),
+            Some(Action::Sync) => self.sync_trigger.notify_one(),
+            Some(Action::View) => self.toggle_view(),
+            None => {}
+        }
+    }
+
+    fn new_event(&mut self) {
+        let mut calendars: Vec<GcalCalendar> = self.calendars.values().filter(|calendar| calendar.sync_enabled).cloned().collect();
+        calendars.sort_by(|a, b| a.name.cmp(&b.name));
+        self.form = EventForm::new_event(self.selected_date, calendars);
+        if self.form.is_none() {
+            self.status = Some("No writable calendars".to_string());
+        }
+    }
+
+    fn edit_selected_event(&mut self) {
+        if let Some((event, calendar)) = self.selected_writable_event() {
+            self.form = EventForm::edit_event(event.clone(), calendar.clone());
+        }
+    }
+
+    /// The highlighted agenda event and its calendar, if the calendar can be written to and the
+    /// event has no sync conflict. Sets the status line otherwise.
+    fn selected_writable_event(&mut self) -> Option<(&CalendarEvent, &GcalCalendar)> {
+        let event = self.agenda_state.selected().and_then(|i| self.agenda.get(i))?;
+        if self.conflicted.contains(&(event.event_id.clone(), event.calendar_id.clone())) {
+            self.status = Some(format!("Resolve the sync conflict first, press {}", self.keys.conflicts));
+            return None;
+        }
+        match self.calendars.get(&event.calendar_id).or_else(|| self.org_calendars.get(&event.calendar_id)) {
+            Some(calendar) if calendar.access.can_write() => Some((event, calendar)),
+            calendar => {
+                let name = calendar.map_or(event.calendar_id.as_str(), |calendar| calendar.name.as_str());
+                self.status = Some(format!("{name} is read-only"));
+                None
+            }
+        }
+    }
+
+    fn ask_delete(&mut self) {
+        let Some(source_type) = self.selected_writable_event().map(|(event, _)| event.source_type.clone()) else {
+            return;
+        };
+        if source_type == SourceType::OrgMode {
+            self.status = Some("Org entries can only be deleted from their file".to_string());
+        } else {
+            self.confirm_delete = true;
+        }
+    }
+
+    /// Save an edited event: org events are written back to their file, other events are stored
+    /// locally and queued for upload
+    fn save_event(&mut self, event: CalendarEvent) {
+        let result = match event.source_type {
+            SourceType::OrgMode => save_org_event(&self.db, &event, &self.org_mirrors).map_err(|error| error.to_string()),
+            SourceType::GoogleCalendar => self.db.lock().unwrap().save_local_event(&event).map_err(|error| error.to_string()),
+        };
+        match result {
+            Ok(()) => {
+                self.form = None;
+                self.status = Some(format!("Saved \"{}\"", event.title));
+                self.upload_trigger.notify_one();
+            }
+            Err(error) => self.status = Some(format!("Unable to save event: {error}")),
+        }
+        self.invalidate();
+    }
+
+    /// Open the checklist of calendars to sync, e.g. before the first import
+    pub fn show_calendar_list(&mut self) {
+        match self.db.lock().unwrap().calendars() {
+            Ok(calendars) => self.calendar_list = Some(CalendarList::new(calendars)),
+            Err(error) => self.status = Some(format!("Unable to load calendars: {error}")),
+        }
+    }
+
+    /// Persist a calendar's checkbox. Unchecking it drops its events right away.
+    fn set_calendar_enabled(&mut self, calendar_id: &str, enabled: bool) {
+        let result = self.db.lock().unwrap().set_calendar_enabled(calendar_id, enabled);
+        if let Err(error) = result {
+            self.status = Some(format!("Unable to update calendar: {error}"));
+            if let Some(calendar_list) = &mut self.calendar_list {
+                calendar_list.set_enabled(calendar_id, !enabled);
+            }
+        }
+        self.invalidate();
+    }
+
+    fn show_conflicts(&mut self) {
+        let result = self.db.lock().unwrap().conflicts();
+        match result.map(ConflictView::new) {
+            Ok(Some(conflict_view)) => self.conflict_view = Some(conflict_view),
+            Ok(None) => self.status = Some("No sync conflicts".to_string()),
+            Err(error) => self.status = Some(format!("Unable to load conflicts: {error}")),
         }
     }
 
+    /// Store the version the user picked, then move on to the remaining conflicts if there are any
+    fn resolve_conflict(&mut self, event: CalendarEvent, remote_etag: &str, upload: bool) {
+        let result = self.db.lock().unwrap().resolve_conflict(&event, remote_etag, upload);
+        if result.is_ok() && upload {
+            self.upload_trigger.notify_one();
+        }
+        self.status = Some(match result {
+            Ok(()) => format!("Resolved \"{}\"", event.title),
+            Err(error) => format!("Unable to resolve conflict: {error}"),
+        });
+        self.conflict_view = self.db.lock().unwrap().conflicts().ok().and_then(ConflictView::new);
+        self.invalidate();
+    }
+
+    fn delete_selected_event(&mut self) {
+        let Some(event) = self.agenda_state.selected().and_then(|i| self.agenda.get(i)) else {
+            return;
+        };
+        let result = self.db.lock().unwrap().delete_local_event(event);
+        if result.is_ok() {
+            self.upload_trigger.notify_one();
+        }
+        self.status = Some(match result {
+            Ok(()) => format!("Deleted \"{}\"", event.title),
+            Err(error) => format!("Unable to delete event: {error}"),
+        });
+        self.invalidate();
+    }
+
     fn exit(&mut self) {
         self.exit = true;
     }
@@ -139,7 +750,7 @@ impl CalendarTextUserInterface {
     }
 
     fn forward(&mut self) {
-        if self.selected_date.day() != Month::from_u32(self.selected_date.month().into()).unwrap().num_days(self.selected_date.year()).unwrap() as u32 {
+        if self.selected_date.day() != Month::from_u32(self.selected_date.month()).unwrap().num_days(self.selected_date.year()).unwrap() as u32 {
             self.selected_date = self.selected_date + Days::new(1);
             self.selected_column += 1;
         }
@@ -182,12 +793,18 @@ impl CalendarTextUserInterface {
         self.selected_column = Self::get_column(self.selected_date, self.width);
     }
 
+    /// Move the selection by whole days, e.g. a week at a time in the week view
+    fn move_days(&mut self, days: i64) {
+        self.set_date(self.selected_date + TimeDelta::days(days));
+        self.saved_column = self.selected_column;
+    }
+
     fn set_date(&mut self, date: NaiveDate) {
         self.selected_date = date;
         self.selected_column = Self::get_column(date, self.width);
     }
 
-    fn build_calendar(&self) -> Vec<Line> {
+    fn build_calendar(&self) -> Vec<Line<'_>> {
         // We need to find the earliest day of the week, with respect to 31 January, since that
         // will be at the end of the calendar. This is with respect to the year corresponding to
         // the selected_date.
@@ -205,9 +822,9 @@ impl CalendarTextUserInterface {
         let jan_31_weekday: u16 = NaiveDate::from_ymd_opt(selected_year, 1, 31).unwrap().weekday() as u16;
 
         // COLORS
-        let column_row_highlight = Color::Indexed(017);
-        let selected_color = Color::Yellow;
-        let current_color = Color::Green;
+        let column_row_highlight = self.theme.highlight;
+        let selected_color = self.theme.selected;
+        let current_color = self.theme.today;
 
         let mut weekday_label: Vec<Span> = Vec::new();
         for n in 1..=self.width {
@@ -278,9 +895,17 @@ impl CalendarTextUserInterface {
                 let day = num_days + 1 - d;
                 let day_position = target_position as i32 - (d as i32);
 
-                let day_str = format!(" {}{}", day, if day < 10 { " " } else { "" });
+                let density = self.density.get(&NaiveDate::from_ymd_opt(selected_year, m, day.into()).unwrap());
+                let glyph = density.map_or(' ', DayDensity::glyph);
+                let day_str = format!("{}{}{}", glyph, day, if day < 10 { " " } else { "" });
                 let mut day_span: Span = day_str.into();
 
+                if let Some(color) = density
+                    .and_then(DayDensity::busiest_calendar)
+                    .and_then(|id| self.calendar_colors.get(id)) {
+                    day_span = day_span.fg(*color)
+                }
+
                 if m == selected_month || day_position + 1 == self.selected_column as i32 {
                     day_span = day_span.bg(column_row_highlight)
                 }
@@ -298,11 +923,11 @@ impl CalendarTextUserInterface {
             // sure that if there is empty sapces on the column, set those
 
             if m == selected_month {
-                for i in 0..(target_position as usize - num_days as usize) {
-                    month_line[i] = "   ".bg(column_row_highlight);
+                for span in &mut month_line[0..(target_position as usize - num_days as usize)] {
+                    *span = "   ".bg(column_row_highlight);
                 }
-                for i in (target_position as usize)..self.width as usize {
-                    month_line[i] = "   ".bg(column_row_highlight);
+                for span in &mut month_line[(target_position as usize)..self.width as usize] {
+                    *span = "   ".bg(column_row_highlight);
                 }
             }
             else {
@@ -317,7 +942,136 @@ impl CalendarTextUserInterface {
         calendar_text
     }
 
-    pub fn build_date(&self) -> Vec<Line> {
+    fn build_agenda(&self) -> List<'static> {
+        let title = Line::from(" [agenda] ".bold());
+        let keys = &self.keys;
+        let instructions = Line::from(vec![
+            " Next ".into(),
+            hint(keys.next_event),
+            " Previous ".into(),
+            hint(keys.previous_event),
+            " Details ".into(),
+            "<Enter>".blue().bold(),
+            " Add ".into(),
+            hint(keys.add),
+            " Edit ".into(),
+            hint(keys.edit),
+            " Delete ".into(),
+            hint(keys.delete),
+            " Conflicts ".into(),
+            hint(keys.conflicts),
+            " ".into(),
+        ]);
+        let agenda_block = Block::bordered()
+            .title(title.centered())
+            .title_bottom(instructions.centered());
+
+        if self.agenda.is_empty() {
+            return List::new([ListItem::new(" No events".italic())]).block(agenda_block);
+        }
+
+        let items = self.agenda.iter().map(|event| {
+            let color = self.calendar_colors.get(&event.calendar_id).copied().unwrap_or(Color::Reset);
+            if let Some((first, last)) = event.all_day_dates() {
+                return ListItem::new(self.build_banner(event, first, last)).bg(color).fg(Color::Black);
+            }
+            let time: Span = if covers_whole_day(event, self.selected_date) {
+                "   all day   ".italic()
+            } else {
+                format!(" {}–{} ",
+                    event.start_time.with_timezone(&Local).format("%H:%M"),
+                    event.end_time.with_timezone(&Local).format("%H:%M")).into()
+            };
+            let conflicted = self.conflicted.contains(&(event.event_id.clone(), event.calendar_id.clone()));
+            let sync_marker: Span = match (&event.upload_error, event.updated) {
+                _ if conflicted => "‼".red().bold(),
+                (Some(_), _) => "!".red().bold(),
+                (None, true) => "↑".dark_gray(),
+                (None, false) => " ".into(),
+            };
+            let mut line = vec![
+                "▌".fg(color),
+                sync_marker,
+                time,
+                event.title.clone().bold(),
+            ];
+            if let Some(location) = &event.location {
+                line.push(format!("  @ {location}").dark_gray());
+            }
+            ListItem::new(Line::from(line))
+        });
+
+        List::new(items)
+            .block(agenda_block)
+            .highlight_style(self.theme.highlight)
+    }
+
+    /// Full-width agenda line for an all-day event, counting days for multi-day events
+    fn build_banner(&self, event: &CalendarEvent, first: NaiveDate, last: NaiveDate) -> Line<'static> {
+        let mut line = vec![" ".into(), event.title.clone().bold()];
+        if first != last {
+            let day = (self.selected_date - first).num_days() + 1;
+            let days = (last - first).num_days() + 1;
+            line.push(format!("  (day {day}/{days})").into());
+        }
+        Line::from(line)
+    }
+
+    fn build_detail<'a>(&'a self, event: &'a CalendarEvent) -> Paragraph<'a> {
+        let calendar = self.calendars.get(&event.calendar_id).or_else(|| self.org_calendars.get(&event.calendar_id));
+        let color = self.calendar_colors.get(&event.calendar_id).copied().unwrap_or(Color::Reset);
+        let label = |text: &'static str| format!("{text:<11}").bold();
+
+        let mut lines = match event.all_day_dates() {
+            Some((first, last)) if first == last => vec![
+                Line::from(vec![label("Date"), first.format("%a %-d %b %Y").to_string().into(), " (all day)".dark_gray()]),
+            ],
+            Some((first, last)) => vec![
+                Line::from(vec![label("Starts"), first.format("%a %-d %b %Y").to_string().into()]),
+                Line::from(vec![label("Ends"), last.format("%a %-d %b %Y").to_string().into(), " (all day)".dark_gray()]),
+            ],
+            None => vec![
+                Line::from(vec![label("Starts"), event.start_time.with_timezone(&Local).format("%a %-d %b %Y %H:%M").to_string().into()]),
+                Line::from(vec![label("Ends"), event.end_time.with_timezone(&Local).format("%a %-d %b %Y %H:%M").to_string().into()]),
+            ],
+        };
+        lines.extend([
+            Line::from(vec![
+                label("Calendar"),
+                "● ".fg(color),
+                calendar.map_or(event.calendar_id.as_str(), |calendar| calendar.name.as_str()).into(),
+                format!(" ({})", calendar.map_or("unknown", |calendar| calendar.access.as_str())).dark_gray(),
+            ]),
+        ]);
+        if let Some(location) = &event.location {
+            lines.push(Line::from(vec![label("Location"), location.as_str().into()]));
+        }
+        if !event.attendees.is_empty() {
+            lines.push(Line::from(vec![label("Attendees"), event.attendees.join(", ").into()]));
+        }
+        if self.conflicted.contains(&(event.event_id.clone(), event.calendar_id.clone())) {
+            lines.push(Line::from(vec![label("Sync"), format!("Conflicts with a remote change, press <{}> to resolve", self.keys.conflicts).red()]));
+        } else if let Some(error) = &event.upload_error {
+            lines.push(Line::from(vec![label("Sync"), format!("Upload failed, will retry: {error}").red()]));
+        } else if event.updated {
+            lines.push(Line::from(vec![label("Sync"), "Waiting to upload".dark_gray()]));
+        }
+        if let Some(description) = &event.description {
+            lines.push(Line::default());
+            lines.extend(description.lines().map(Line::from));
+        }
+
+        let detail_block = Block::bordered()
+            .title(Line::from(format!(" {} ", event.title).bold()).centered())
+            .title_bottom(Line::from(vec![" Close ".into(), "<Esc>".blue().bold(), " ".into()]).centered())
+            .border_set(border::THICK);
+
+        Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(detail_block)
+    }
+
+    pub fn build_date(&self) -> Vec<Line<'_>> {
         let first_line: Line = match self.selected_date.weekday() { // Make sure no integer underflow by adding the right multiple of 7s based on the width.
             Weekday::Mon => "Monday".into(),
             Weekday::Tue => "Tuesday".into(),
@@ -344,26 +1098,61 @@ impl CalendarTextUserInterface {
             Month::December => "DEC",
         }, self.selected_date.year()).into();
 
-        vec![first_line, second_line.into()]
+        vec![first_line, second_line]
+    }
+}
+
+/// Rect of the given percentage size centered in `area`, for popups
+fn centered(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
+    let [area] = Layout::horizontal([Constraint::Percentage(percent_x)]).flex(Flex::Center).areas(area);
+    let [area] = Layout::vertical([Constraint::Percentage(percent_y)]).flex(Flex::Center).areas(area);
+    area
+}
+
+/// Whether the event lasts from local midnight to midnight on `day`
+fn covers_whole_day(event: &CalendarEvent, day: NaiveDate) -> bool {
+    let (start, end) = event.local_span();
+    start <= local_day_start(day) && end >= local_day_start(day + Days::new(1))
+}
+
+/// Instruction hint for a configurable key
+fn hint(key: char) -> Span<'static> {
+    format!("<{key}>").blue().bold()
+}
+
+/// Parse a "#rrggbb" calendar colour as stored in calendars.color
+pub fn parse_hex_color(hex: &str) -> Option<Color> {
+    let hex = hex.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
     }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(Color::Rgb((value >> 16) as u8, (value >> 8) as u8, value as u8))
 }
 
 impl Widget for &CalendarTextUserInterface {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let title = Line::from(" [calendar] ".bold());
+        let keys = &self.keys;
         let instructions = Line::from(vec![
             " Back (day) ".into(),
-            "<h>".blue().bold(),
+            hint(keys.back),
             " Down (month) ".into(),
-            "<j>".blue().bold(),
+            hint(keys.down),
             " Up (month) ".into(),
-            "<k>".blue().bold(),
+            hint(keys.up),
             " Forward (day) ".into(),
-            "<l>".blue().bold(),
+            hint(keys.forward),
             " Today ".into(),
-            "<t>".blue().bold(),
+            hint(keys.today),
+            " Calendars ".into(),
+            hint(keys.calendars),
+            " Sync ".into(),
+            hint(keys.sync),
+            " Week view ".into(),
+            hint(keys.view),
             " Quit ".into(),
-            "<q>".blue().bold(),
+            hint(keys.quit),
         ]);
         let calendar_block = Block::bordered()
             .title(title.centered())
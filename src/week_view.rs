@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, Weekday};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::Line,
+    widgets::{Clear, Paragraph, Widget},
+};
+
+use crate::{
+    config::Theme,
+    event::{local_day_start, CalendarEvent},
+};
+
+/// First hour shown when the grid is too short for the whole day, unless an event starts earlier
+const DEFAULT_FIRST_HOUR: i64 = 8;
+const AXIS_WIDTH: u16 = 6;
+
+/// Seven days side by side on an hourly grid, with all-day events above it
+pub struct WeekView<'a> {
+    start: NaiveDate,
+    selected_date: NaiveDate,
+    now: DateTime<Local>,
+    events: &'a [CalendarEvent], // Events overlapping the week
+    colors: &'a HashMap<String, Color>,
+    theme: Theme,
+}
+
+/// The day starting the week `date` is in
+pub fn first_day_of_week(date: NaiveDate, week_start: Weekday) -> NaiveDate {
+    date - Days::new(date.weekday().days_since(week_start).into())
+}
+
+impl<'a> WeekView<'a> {
+    pub fn new(selected_date: NaiveDate, week_start: Weekday, events: &'a [CalendarEvent], colors: &'a HashMap<String, Color>, theme: Theme) -> Self {
+        let start = first_day_of_week(selected_date, week_start);
+        let now = Local::now();
+        Self { start, selected_date, now, events, colors, theme }
+    }
+
+    fn days(&self) -> impl Iterator<Item = NaiveDate> {
+        self.start.iter_days().take(7)
+    }
+
+    /// Timed events on `day` as minutes since its midnight, clipped to the day
+    fn timed_events(&self, day: NaiveDate) -> Vec<(&CalendarEvent, i64, i64)> {
+        let day_start = local_day_start(day);
+        let day_end = local_day_start(day + Days::new(1));
+        self.events.iter()
+            .filter(|event| !event.all_day)
+            .filter_map(|event| {
+                let (start, end) = event.local_span();
+                // Events without a duration still show up on the day they are at
+                if start >= day_end || end <= day_start && start < day_start {
+                    return None;
+                }
+                let start = (start.max(day_start) - day_start).num_minutes();
+                let end = (end.min(day_end) - day_start).num_minutes();
+                Some((event, start, end.max(start + 1)))
+            })
+            .collect()
+    }
+}
+
+/// Column and number of columns of each (start, end) span, so that overlapping spans sit side by
+/// side. Spans overlapping each other, directly or through others, get the same number of columns.
+fn layout_columns(spans: &[(i64, i64)]) -> Vec<(usize, usize)> {
+    let mut order: Vec<usize> = (0..spans.len()).collect();
+    order.sort_by_key(|&i| (spans[i].0, std::cmp::Reverse(spans[i].1)));
+
+    let mut layout = vec![(0, 1); spans.len()];
+    let mut group: Vec<usize> = Vec::new();
+    let mut column_ends: Vec<i64> = Vec::new();
+    let mut group_end = i64::MIN;
+    for i in order {
+        let (start, end) = spans[i];
+        if start >= group_end {
+            for &member in &group {
+                layout[member].1 = column_ends.len();
+            }
+            group.clear();
+            column_ends.clear();
+            group_end = end;
+        }
+        let column = match column_ends.iter().position(|&column_end| column_end <= start) {
+            Some(column) => column,
+            None => {
+                column_ends.push(end);
+                column_ends.len() - 1
+            }
+        };
+        column_ends[column] = end;
+        layout[i].0 = column;
+        group.push(i);
+        group_end = group_end.max(end);
+    }
+    for &member in &group {
+        layout[member].1 = column_ends.len();
+    }
+    layout
+}
+
+impl Widget for &WeekView<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let [header_area, all_day_area, grid_area] = Layout::vertical([Constraint::Length(1), Constraint::Length(1), Constraint::Min(0)]).areas(area);
+        let columns = Layout::horizontal([Constraint::Length(AXIS_WIDTH), Constraint::Fill(1)]);
+        let [_, header_days] = columns.areas(header_area);
+        let [_, all_day_days] = columns.areas(all_day_area);
+        let [axis_area, grid_days] = columns.areas(grid_area);
+        let day_columns = Layout::horizontal([Constraint::Fill(1); 7]);
+        let today = self.now.date_naive();
+
+        // Fit as many rows per hour as the height allows, showing part of the day if it doesn't
+        // fit at all
+        let rows_per_hour = i64::from(grid_area.height / 24).clamp(1, 4);
+        let visible_hours = (i64::from(grid_area.height) / rows_per_hour).min(24);
+        let earliest = self.days()
+            .flat_map(|day| self.timed_events(day))
+            .map(|(_, start, _)| start / 60)
+            .min();
+        let first_hour = earliest.unwrap_or(DEFAULT_FIRST_HOUR).min(DEFAULT_FIRST_HOUR).min(24 - visible_hours);
+        let row = |minutes: i64| (minutes - first_hour * 60) * rows_per_hour / 60;
+        let row_end = |minutes: i64| ((minutes - first_hour * 60) * rows_per_hour + 59).div_euclid(60);
+        let visible_rows = visible_hours * rows_per_hour;
+
+        for hour in first_hour..first_hour + visible_hours {
+            let y = grid_area.y + row(hour * 60) as u16;
+            buf.set_string(axis_area.x, y, format!("{hour:02}:00"), Style::new().dark_gray());
+        }
+
+        let headers: [Rect; 7] = day_columns.areas(header_days);
+        let all_day_rows: [Rect; 7] = day_columns.areas(all_day_days);
+        let grid_columns: [Rect; 7] = day_columns.areas(grid_days);
+        for (i, day) in self.days().enumerate() {
+            let (header, all_day, column) = (headers[i], all_day_rows[i], grid_columns[i]);
+            let mut label = Line::from(day.format("%a %d").to_string()).centered();
+            if day == self.selected_date {
+                label = label.bg(self.theme.highlight).fg(self.theme.selected).bold();
+            } else if day == today {
+                label = label.fg(self.theme.today).bold();
+            }
+            label.render(header, buf);
+
+            let all_day_events: Vec<&CalendarEvent> = self.events.iter()
+                .filter(|event| event.all_day_dates().is_some_and(|(first, last)| first <= day && day <= last))
+                .collect();
+            if let Some(first) = all_day_events.first() {
+                let color = self.colors.get(&first.calendar_id).copied().unwrap_or(Color::Gray);
+                let text = match all_day_events.len() {
+                    1 => first.title.clone(),
+                    count => format!("{} +{}", first.title, count - 1),
+                };
+                Paragraph::new(format!(" {text}")).bg(color).fg(Color::Black).render(all_day, buf);
+            }
+
+            // Day separator and hour lines, drawn under the events
+            for y in column.y..column.y + column.height {
+                buf.set_string(column.x, y, "│", Style::new().dark_gray());
+            }
+            for hour in first_hour..first_hour + visible_hours {
+                let y = column.y + row(hour * 60) as u16;
+                buf.set_string(column.x + 1, y, "╌".repeat(column.width.saturating_sub(1).into()), Style::new().dark_gray());
+            }
+
+            let inner = Rect { x: column.x + 1, width: column.width.saturating_sub(1), ..column };
+            let events = self.timed_events(day);
+            let spans: Vec<(i64, i64)> = events.iter().map(|(_, start, end)| (*start, *end)).collect();
+            for ((event, start, end), (index, count)) in events.iter().zip(layout_columns(&spans)) {
+                let top = row(*start).max(0);
+                if *end <= first_hour * 60 || top >= visible_rows {
+                    continue;
+                }
+                let bottom = row_end(*end).min(visible_rows).max(top + 1);
+                let (count, index) = (count as u16, index as u16);
+                let x = inner.x + inner.width * index / count;
+                let width = inner.x + inner.width * (index + 1) / count - x;
+                if width == 0 {
+                    continue;
+                }
+                let block = Rect { x, y: inner.y + top as u16, width, height: (bottom - top) as u16 };
+                let color = self.colors.get(&event.calendar_id).copied().unwrap_or(Color::Gray);
+                let time = format!("{}–{}",
+                    event.start_time.with_timezone(&Local).format("%H:%M"),
+                    event.end_time.with_timezone(&Local).format("%H:%M"));
+                Clear.render(block, buf);
+                Paragraph::new(vec![Line::from(event.title.clone()).bold(), Line::from(time)])
+                    .bg(color)
+                    .fg(Color::Black)
+                    .render(block, buf);
+            }
+
+            if day == today {
+                let minutes = (self.now - local_day_start(today)).num_minutes();
+                let now_row = row(minutes);
+                if (0..visible_rows).contains(&now_row) && minutes >= first_hour * 60 {
+                    let y = inner.y + now_row as u16;
+                    buf.set_string(inner.x, y, "─".repeat(inner.width.into()), Style::new().red());
+                    buf.set_string(axis_area.x, y, self.now.format("%H:%M").to_string(), Style::new().red().bold());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_events_share_the_width() {
+        let spans = [(9 * 60, 10 * 60), (9 * 60 + 30, 11 * 60), (10 * 60, 10 * 60 + 30), (12 * 60, 13 * 60)];
+        // The third event reuses the first column, freed at 10:00. The last one overlaps nothing.
+        assert_eq!(layout_columns(&spans), [(0, 2), (1, 2), (0, 2), (0, 1)]);
+        assert_eq!(first_day_of_week(NaiveDate::from_ymd_opt(2025, 6, 4).unwrap(), Weekday::Sun), NaiveDate::from_ymd_opt(2025, 6, 1).unwrap());
+        assert_eq!(first_day_of_week(NaiveDate::from_ymd_opt(2025, 6, 2).unwrap(), Weekday::Mon), NaiveDate::from_ymd_opt(2025, 6, 2).unwrap());
+    }
+}